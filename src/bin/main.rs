@@ -1,46 +1,153 @@
+use bort::catalog::{
+    parse_item_file, CatalogChanges, CatalogItem, ItemCatalog, ItemDefinition, ItemFilter,
+    ItemKind,
+};
+use bort::db::Db;
+use bort::feedback::{FeedbackStore, RateOutcome, Reputation, MAX_COMMENT_LENGTH, SCORE_RANGE};
+use bort::guilds::{GuildSettings, GuildStore, Market};
+use bort::history::{HistoryStore, PriceStats};
+use bort::item_index::ItemIndex;
+use bort::listings::{
+    DistanceMetric, ItemQuery, Listing, ListingField, ListingSearch, ListingStore, PriceFilter,
+    SortOrder,
+};
+use bort::matching::find_matches;
+use bort::preferences::{Home, PreferenceStore, SavedLocation};
+use bort::trades::{complete_listing, CompleteOutcome};
+use bort::watches::{Watch, WatchStore};
+use bort::Error;
 use clokwerk::AsyncScheduler;
 use clokwerk::TimeUnits;
-use csv::ReaderBuilder;
 use futures::Stream;
-use memchr::memmem;
 use poise::serenity_prelude as serenity;
 use prettytable::format;
 use prettytable::row;
+use prettytable::Row;
 use prettytable::Table;
-use rusqlite::{params, Connection, Result};
-use serde::Deserialize;
+use rusqlite::Result;
 use std::collections::HashMap;
 use std::io::Read;
-use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 use std::{env, fs::File};
 
 struct Data {
-    item_list: HashMap<String, bool>,
+    items: ItemsHandle,
+    listing_lifetime_days: i64,
+    /// How far apart complementary listings can be & still be matched
+    match_distance: i32,
+    db: Db,
 }
-type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, Error>;
-struct Listing {
-    id: i32,
-    offer_quantity: i32,
-    offer_item: String,
-    request_quantity: i32,
-    request_item: String,
-    location_north: i32,
-    location_east: i32,
-    user: String,
-    offer_count: i32,
-    description: String,
+
+/// The item catalog & its autocomplete index, built from the item files
+struct Items {
+    catalog: ItemCatalog,
+    /// Item names ranked for autocomplete
+    index: ItemIndex,
+}
+
+impl Items {
+    fn new(catalog: ItemCatalog) -> Self {
+        Items {
+            index: ItemIndex::new(catalog.items().iter().map(|item| item.display_name())),
+            catalog,
+        }
+    }
 }
 
-enum ItemQuery {
-    SellingItem,
-    BuyingItem,
+/// Shared handle to the current items, replaced whole when the item files are reloaded so
+/// commands keep a consistent catalog while they run
+#[derive(Clone)]
+struct ItemsHandle {
+    items: Arc<RwLock<Arc<Items>>>,
+    /// Held for a whole reload, so reloads can't overlap & swap in an older catalog or report
+    /// changes against one
+    reloading: Arc<tokio::sync::Mutex<()>>,
 }
 
-#[derive(Debug, Deserialize)]
-struct Item {
-    name: String,
-    tier: i32,
+impl ItemsHandle {
+    fn new(items: Items) -> Self {
+        ItemsHandle {
+            items: Arc::new(RwLock::new(Arc::new(items))),
+            reloading: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    fn get(&self) -> Arc<Items> {
+        self.items.read().unwrap().clone()
+    }
+
+    fn set(&self, items: Arc<Items>) {
+        *self.items.write().unwrap() = items;
+    }
+}
+
+/// Item data files & the kind of item each lists
+const ITEM_FILES: [(&str, ItemKind); 2] = [
+    ("items_cargo_data_utf16.txt", ItemKind::Cargo),
+    ("items_item_data_utf16.txt", ItemKind::Item),
+];
+/// Names listed per section of the item reload report
+const REPORTED_NAMES: usize = 10;
+
+/// How long paged results can be flipped through after the last button press
+const PAGE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Listings shown per page as embeds, Discord allows up to 10 embeds per message
+const EMBEDS_PER_PAGE: usize = 5;
+
+/// How listings are displayed
+#[derive(Debug, Clone, Copy, Default, PartialEq, poise::ChoiceParameter)]
+enum DisplayStyle {
+    /// A card per listing
+    #[default]
+    #[name = "embed"]
+    Embed,
+    /// A plain text table
+    #[name = "table"]
+    Table,
+}
+
+/// One page of listings, as sent to Discord
+struct Page {
+    /// Shown above the listings, e.g. which tier they're from
+    heading: String,
+    body: PageBody,
+}
+
+enum PageBody {
+    Table(String),
+    Embeds(Vec<serenity::CreateEmbed>),
+}
+
+/// Listing expiry settings, read from the environment
+struct ExpiryConfig {
+    /// Days a listing stays up before it is removed
+    lifetime_days: i64,
+    /// Minutes between runs of the expiry task
+    check_interval_minutes: u32,
+}
+
+/// Minutes between checks of the item files for changes, unset to only reload with /reload_items
+fn item_reload_check_minutes() -> Option<u32> {
+    env::var("ITEM_RELOAD_CHECK_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+}
+
+impl ExpiryConfig {
+    fn from_env() -> Self {
+        ExpiryConfig {
+            lifetime_days: env::var("LISTING_LIFETIME_DAYS")
+                .ok()
+                .and_then(|days| days.parse().ok())
+                .unwrap_or(5),
+            check_interval_minutes: env::var("EXPIRY_CHECK_MINUTES")
+                .ok()
+                .and_then(|minutes| minutes.parse().ok())
+                .unwrap_or(10),
+        }
+    }
 }
 
 #[tokio::main]
@@ -48,25 +155,28 @@ async fn main() {
     println!("Starting up...");
     dotenv::dotenv().ok();
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
+    let expiry = ExpiryConfig::from_env();
+    let database_path = env::var("DATABASE_PATH").unwrap_or("db.db3".to_string());
+
+    let db = Db::open(&database_path).expect("Db failed");
 
     println!("Loading items...");
+    let items_modified = item_files_modified();
+    let loaded_items = ItemsHandle::new(load_items(&db).await.expect("Could not load items"));
 
-    let cargo_items =
-        load_items_from_file("items_cargo_data_utf16.txt").expect("Could not load items");
-    let item_items =
-        load_items_from_file("items_item_data_utf16.txt").expect("Could not load items");
-    let mut item_map: HashMap<String, bool> = HashMap::new();
-    for item in cargo_items.iter().chain(item_items.iter()) {
-        let name_with_tier = if item.tier != -1 {
-            format!("{} (T{})", item.name, item.tier)
-        } else {
-            item.name.clone()
-        };
-        item_map.insert(name_with_tier, true);
-    }
+    let lifetime_days = expiry.lifetime_days;
+    db.call(move |db| ListingStore::new(db).backfill_expiry(lifetime_days))
+        .await
+        .expect("Expiry backfill failed");
 
     let data = Data {
-        item_list: item_map,
+        items: loaded_items.clone(),
+        listing_lifetime_days: expiry.lifetime_days,
+        match_distance: env::var("MATCH_DISTANCE")
+            .ok()
+            .and_then(|distance| distance.parse().ok())
+            .unwrap_or(1000),
+        db: db.clone(),
     };
 
     let intents = serenity::GatewayIntents::GUILD_MESSAGES
@@ -78,71 +188,384 @@ async fn main() {
             commands: vec![
                 list(),
                 unlist(),
-                nearby_buyers(),
-                nearby_sellers(),
+                renew(),
+                complete(),
+                rate(),
+                edit_listing(),
+                poise::Command {
+                    prefix_action: nearby_buyers_prefix().prefix_action,
+                    ..nearby_buyers()
+                },
+                poise::Command {
+                    prefix_action: nearby_sellers_prefix().prefix_action,
+                    ..nearby_sellers()
+                },
                 nearby_listings(),
                 info(),
                 my_listings(),
+                set_home(),
+                location(),
+                watch(),
+                unwatch(),
+                price(),
+                items(),
+                market(),
+                reload_items(),
                 help(),
             ],
             ..Default::default()
         })
-        .setup(|ctx, _ready, framework| {
+        .setup(|ctx, ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+                let guild_ids = ready.guilds.iter().map(|guild| guild.id).collect();
+                tokio::spawn(migrate_owner_ids(
+                    data.db.clone(),
+                    ctx.http.clone(),
+                    guild_ids,
+                ));
                 Ok(data)
             })
         })
         .build();
 
-    let client = serenity::ClientBuilder::new(token, intents)
+    let mut client = serenity::ClientBuilder::new(token, intents)
         .framework(framework)
+        .await
+        .expect("Client failed");
+
+    let http = client.http.clone();
+    let mut scheduler = AsyncScheduler::new();
+    if let Some(minutes) = item_reload_check_minutes() {
+        let db = db.clone();
+        let last_modified = Arc::new(Mutex::new(items_modified));
+        scheduler.every(minutes.minutes()).run(move || {
+            reload_changed_items(db.clone(), loaded_items.clone(), last_modified.clone())
+        });
+    }
+    scheduler
+        .every(expiry.check_interval_minutes.minutes())
+        .run(move || expire_listings(db.clone(), http.clone()));
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+
+    println!("Awaiting messages...");
+    client.start().await.unwrap();
+}
+
+/// Match listings posted before owners were tracked by ID to guild members with the same name
+async fn migrate_owner_ids(
+    db: Db,
+    http: Arc<serenity::Http>,
+    guild_ids: Vec<serenity::GuildId>,
+) {
+    let unclaimed = db
+        .call(|db| ListingStore::new(db).count_unclaimed())
+        .await;
+    if !matches!(unclaimed, Ok(count) if count > 0) {
+        return;
+    }
+    let mut owners = HashMap::<String, u64>::new();
+    for guild_id in guild_ids {
+        let mut after = None;
+        loop {
+            let members = match guild_id.members(&http, Some(1000), after).await {
+                Ok(members) => members,
+                Err(err) => {
+                    println!("Failed to fetch members of guild {}: {}", guild_id, err);
+                    break;
+                }
+            };
+            for member in &members {
+                owners.insert(member.user.name.clone(), member.user.id.get());
+            }
+            match members.last() {
+                Some(member) if members.len() == 1000 => after = Some(member.user.id),
+                _ => break,
+            }
+        }
+    }
+    let result = db
+        .call(move |db| {
+            let store = ListingStore::new(db);
+            let mut migrated = 0;
+            for (username, user_id) in owners {
+                migrated += store.claim_legacy(user_id, &username)?;
+            }
+            Ok(migrated)
+        })
+        .await;
+    match result {
+        Ok(migrated) => println!("Migrated {} listings to owner IDs", migrated),
+        Err(err) => println!("Failed to migrate owner IDs: {}", err),
+    }
+}
+
+/// Read the item files & give each item its ID
+async fn load_items(db: &Db) -> Result<Items, Error> {
+    let mut definitions = Vec::new();
+    for (file_name, kind) in ITEM_FILES {
+        definitions.extend(load_items_from_file(file_name, kind)?);
+    }
+    let catalog = db
+        .call(move |db| ItemCatalog::sync(db, definitions))
+        .await?;
+    Ok(Items::new(catalog))
+}
+
+/// Reload the item files in place, returning a report of what changed
+async fn reload_item_files(db: &Db, handle: &ItemsHandle) -> Result<String, Error> {
+    let _reloading = handle.reloading.lock().await;
+    let items = Arc::new(load_items(db).await?);
+    let changes = handle.get().catalog.changes(&items.catalog);
+    handle.set(items.clone());
+    let count = items.catalog.items().len();
+    let orphaned = db
+        .call(move |db| items.catalog.orphaned_items(db))
+        .await?;
+    Ok(format_reload_report(count, &changes, &orphaned))
+}
+
+/// Last modified times of the item files
+fn item_files_modified() -> Vec<Option<SystemTime>> {
+    ITEM_FILES
+        .iter()
+        .map(|(file_name, _)| {
+            std::fs::metadata(file_name)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Reload the items if the item files changed since they were last loaded
+async fn reload_changed_items(
+    db: Db,
+    items: ItemsHandle,
+    last_modified: Arc<Mutex<Vec<Option<SystemTime>>>>,
+) {
+    let modified = item_files_modified();
+    if *last_modified.lock().unwrap() == modified {
+        return;
+    }
+    // Only remember the change once it's loaded, so files caught half written are retried
+    match reload_item_files(&db, &items).await {
+        Ok(report) => {
+            *last_modified.lock().unwrap() = modified;
+            println!("Reloaded changed item files. {}", report);
+        }
+        Err(err) => println!("Failed to reload item files: {}", err),
+    }
+}
+
+/// Describe a reload of the item files
+fn format_reload_report(
+    count: usize,
+    changes: &CatalogChanges,
+    orphaned: &[(String, i32)],
+) -> String {
+    let names = |names: Vec<String>| {
+        let mut listed = names
+            .iter()
+            .take(REPORTED_NAMES)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ");
+        if names.len() > REPORTED_NAMES {
+            listed.push_str(&format!(" & {} more", names.len() - REPORTED_NAMES));
+        }
+        listed
+    };
+    let mut report = format!("Loaded {} items.", count);
+    if changes.is_empty() {
+        report.push_str(" No items changed.");
+    }
+    if !changes.added.is_empty() {
+        report.push_str(&format!(
+            "\nAdded {}: {}",
+            changes.added.len(),
+            names(changes.added.clone())
+        ));
+    }
+    if !changes.removed.is_empty() {
+        report.push_str(&format!(
+            "\nRemoved {}: {}",
+            changes.removed.len(),
+            names(changes.removed.clone())
+        ));
+    }
+    if !changes.renamed.is_empty() {
+        report.push_str(&format!(
+            "\nRenamed {}: {}",
+            changes.renamed.len(),
+            names(
+                changes
+                    .renamed
+                    .iter()
+                    .map(|(old, new)| format!("{} -> {}", old, new))
+                    .collect()
+            )
+        ));
+    }
+    if !orphaned.is_empty() {
+        report.push_str(&format!(
+            "\nListings trading items no longer in the item files: {}",
+            names(
+                orphaned
+                    .iter()
+                    .map(|(item, count)| format!("{} ({})", item, count))
+                    .collect()
+            )
+        ));
+    }
+    report
+}
+
+/// Remove expired listings & warn owners of listings expiring within a day. Owners that couldn't be
+/// reached are warned again on the next run, unless Discord says a DM can never reach them
+async fn expire_listings(db: Db, http: Arc<serenity::Http>) {
+    let result = db
+        .call(|db| {
+            let store = ListingStore::new(db);
+            let deleted = store.delete_expired()?;
+            let (expiring, reputations) = with_reputations(db, store.expiring()?)?;
+            Ok((deleted, expiring, reputations))
+        })
         .await;
+    let (expiring, reputations) = match result {
+        Ok((deleted, expiring, reputations)) => {
+            if deleted > 0 {
+                println!("Deleted {} expired listings", deleted);
+            }
+            (expiring, reputations)
+        }
+        Err(err) => {
+            println!("Failed to expire listings: {}", err);
+            return;
+        }
+    };
+    for listing in expiring {
+        let Some(user_id) = listing.user_id else {
+            continue;
+        };
+        let listing_id = listing.id;
+        let message = format!(
+            "Your listing {} will expire in less than a day. Use /renew listing_id: {} to keep it up:\n{}",
+            listing_id,
+            listing_id,
+            format_listing(listing, &reputations),
+        );
+        if let Err(err) = send_dm(&http, user_id, message).await {
+            println!("Failed to send expiry notice to {}: {}", user_id, err);
+            if !is_undeliverable(&err) {
+                continue;
+            }
+        }
+        let marked = db
+            .call(move |db| ListingStore::new(db).mark_expiry_notified(listing_id))
+            .await;
+        if let Err(err) = marked {
+            println!("Failed to mark listing {} as notified: {}", listing_id, err);
+        }
+    }
+}
 
-    let db = Connection::open("db.db3").expect("Db failed");
-    db.execute(
-        "CREATE TABLE IF NOT EXISTS listings (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            sale_quantity int,
-            sale_item text,
-            buy_quantity int,
-            buy_item text,
-            location_north int,
-            location_east int,
-            username text,
-            offer_count int,
-            description text DEFAULT '',
-            timestamp timestamp DEFAULT CURRENT_TIMESTAMP
-        )",
-        (),
+/// Discord error codes for DMs that will never get through: an unknown user, or one who doesn't
+/// accept DMs from the bot
+const UNDELIVERABLE_DM_CODES: [isize; 2] = [10013, 50007];
+
+/// Whether a DM failed in a way that retrying won't fix
+fn is_undeliverable(err: &Error) -> bool {
+    matches!(
+        err.downcast_ref::<serenity::Error>(),
+        Some(serenity::Error::Http(serenity::HttpError::UnsuccessfulRequest(response)))
+            if UNDELIVERABLE_DM_CODES.contains(&response.error.code)
     )
-    .expect("Table create failed");
-
-
-    // let mut scheduler = AsyncScheduler::new();
-    // scheduler.every(10.minutes()).run(move || {
-    //     Box::pin(async move {
-    //         println!("Cleaning up old listings");
-    //         let db = Connection::open("db.db3").expect("Db failed");
-    //         let deleted_listings = db
-    //             .execute(
-    //                 "DELETE FROM listings WHERE timestamp <= datetime('now', '-5 days')",
-    //                 (),
-    //             )
-    //             .expect("Failed to delete old listings");
-    //         println!("Deleted {} listings", deleted_listings);
-    //     })
-    // });
-
-    // tokio::spawn(async move {
-    //     loop {
-    //         scheduler.run_pending().await;
-    //         tokio::time::sleep(Duration::from_millis(100)).await;
-    //     }
-    // });
+}
 
-    println!("Awaiting messages...");
-    client.unwrap().start().await.unwrap();
+/// Send a direct message to a user
+async fn send_dm(http: &serenity::Http, user_id: u64, message: String) -> Result<(), Error> {
+    let channel = serenity::UserId::new(user_id)
+        .create_dm_channel(http)
+        .await?;
+    channel.say(http, message).await?;
+    Ok(())
+}
+
+/// Tell users watching for an item about a new listing selling it
+async fn notify_watchers(
+    http: &serenity::Http,
+    listing: &Listing,
+    watches: Vec<Watch>,
+    reputations: &HashMap<u64, Reputation>,
+) {
+    let mut notified = Vec::<u64>::new();
+    for watch in watches {
+        // One message per user, even if several of their watches match
+        if notified.contains(&watch.user_id) {
+            continue;
+        }
+        notified.push(watch.user_id);
+        let message = format!(
+            "A listing for {} you're watching was just posted:\n{}",
+            listing.offer_item,
+            format_listing(listing.clone(), reputations),
+        );
+        if let Err(err) = send_dm(http, watch.user_id, message).await {
+            println!("Failed to send watch notice to {}: {}", watch.user_id, err);
+        }
+    }
+}
+
+/// Tell the owners of a new listing & its matches about each other
+async fn notify_matches(
+    http: &serenity::Http,
+    listing: &Listing,
+    matches: Vec<Listing>,
+    reputations: &HashMap<u64, Reputation>,
+) {
+    if matches.is_empty() {
+        return;
+    }
+    if let Some(user_id) = listing.user_id {
+        let mut header = format!(
+            "Your listing {} matches {} listings nearby:\n",
+            listing.id,
+            matches.len(),
+        );
+        // DMs can't be paged through, so send every page
+        for page in format_listings(matches.clone(), reputations) {
+            if let Err(err) = send_dm(http, user_id, format!("{}{}", header, page)).await {
+                println!("Failed to send match notice to {}: {}", user_id, err);
+                break;
+            }
+            header.clear();
+        }
+    }
+    for matched in matches {
+        let Some(user_id) = matched.user_id else {
+            continue;
+        };
+        let message = format!(
+            "Your listing {} matches a new listing nearby:\n{}",
+            matched.id,
+            format_listing(
+                Listing {
+                    distance: matched.distance,
+                    ..listing.clone()
+                },
+                reputations,
+            ),
+        );
+        if let Err(err) = send_dm(http, user_id, message).await {
+            println!("Failed to send match notice to {}: {}", user_id, err);
+        }
+    }
 }
 
 /// Help command
@@ -152,30 +575,76 @@ async fn help(ctx: Context<'_>) -> Result<(), Error> {
     let help_message = "
     Use a forward slash '/' to use BRT commands. BRT will respond in DMs or server channels. 
 
-    1. /list - Creates a new listing to advertise to other players. Offer count is optional! 
+    1. /list - Creates a new listing to advertise to other players. Offer count is optional! If someone nearby wants the opposite trade at a price that works for both of you, you'll both get a DM. 
         (ex: /list offer_quantity: 1 offer_item: Rough Cloth (T1) request_quantity: 100 request_item: Hex Coin location_north: 1000 location_east: 1000)
 
     2. /unlist - Remove one of your own listings. Use /my_listings to get the IDs of your listings. 
         (ex: /unlist listing_id: 10)
 
-    3. /info - Get more information about a listing by ID. 
+    3. /renew - Keep one of your listings up for longer. Leave out the ID to renew all of your listings. 
+        (ex: /renew listing_id: 10)
+
+    4. /complete - Record a trade on one of your listings. Its stock goes down by the quantity traded & it's removed once sold out. Name who you traded with and they'll get a DM. 
+        (ex: /complete listing_id: 10 quantity: 2 counterparty: @trader)
+
+    5. /rate - Rate someone you traded with from 1 to 5, once per listing. Ratings show up next to their name in listings. 
+        (ex: /rate user: @trader listing_id: 10 score: 5 comment: Quick & friendly)
+
+    6. /edit_listing - Change the quantities, stock, description or location of one of your listings. The listing keeps its ID. 
+        (ex: /edit_listing listing_id: 10 offer_count: 5)
+
+    7. /info - Get more information about a listing by ID. Searches & /info show listings as cards, add style: table for a plain table instead. 
         (ex: /info listing_id: 10)
 
-    4. /my_listings - Display a list of your own listings. 
+    8. /my_listings - Display a list of your own listings. 
         (ex: /my_listings)
 
-    5. /nearby_listings - Search nearby for any available listings. 
-        (ex: /nearby_listings location_north: 1000 location_east: 1000 distance: 100)
+    9. /nearby_listings - Search nearby for any available listings. Searches a square by default, add metric: circle for a true radius or metric: ring with min_distance to skip listings too close by. 
+        (ex: /nearby_listings location_north: 1000 location_east: 1000 distance: 100 metric: circle)
 
-    6. /nearby_sellers - Search nearby for users interested in selling the specified item. 
-        (/nearby_sellers item: Rough Cloth (T1) location_north: 1000 location_east: 1000 distance: 100)
+    10. /nearby_sellers - Search nearby for users interested in selling the specified item. Results are closest first, add sort: unit price to see the cheapest first. Set counter_item with min_price and/or max_price to only see offers in that price range. 
+        (/nearby_sellers item: Rough Cloth (T1) location_north: 1000 location_east: 1000 distance: 100 counter_item: Hex Coin max_price: 50)
+        Give the item without its tier, or set min_tier and/or max_tier, to search every tier of it at once, grouped by tier.
+        (/nearby_sellers item: Rough Cloth min_tier: 2 max_tier: 4 location_north: 1000 location_east: 1000 distance: 100)
 
-    8. /nearby_buyers - Search nearby for users interested in buying the specified item. 
+    11. /nearby_buyers - Search nearby for users interested in buying the specified item. 
         (ex: /nearby_buyers item: Rough Cloth (T1) location_north: 1000 location_east: 1000 distance: 100)
+        Typed as prefix commands instead, /nearby_sellers & /nearby_buyers only take item, location_north, location_east, distance, counter_item, min_price, max_price & sort. Saved locations, tier search, metric, min_distance, max_age_days & style are slash command only.
+
+    12. /set_home - Save where you trade from & how far you like to search. /list & the nearby searches use it when you leave out the location or distance. 
+        (ex: /set_home location_north: 1000 location_east: 1000 radius: 100)
+
+    13. /location - Save the places you trade from by name, then pick one as location on /list & the nearby searches instead of typing coordinates. Use /location list to see them & /location remove to delete one. 
+        (ex: /location add name: Main base location_north: 1000 location_east: 1000)
+
+    14. /watch - Get a DM whenever someone nearby lists the specified item for sale. Add counter_item & max_price to only hear about good deals. 
+        (ex: /watch item: Rough Cloth (T1) location_north: 1000 location_east: 1000 distance: 100 counter_item: Hex Coin max_price: 50)
+
+    15. /unwatch - Stop watching an item. 
+        (ex: /unwatch item: Rough Cloth (T1))
 
-    8. /help - Display this message :)
+    16. /price - See what an item has been listed for over the last day, week & month. Add counter_item to pick what the price is given in. 
+        (ex: /price item: Rough Cloth (T1) counter_item: Hex Coin)
+
+    17. /items - Look up item names. Filter by part of the name, kind (cargo or item) and tier, & see how many listings trade each one. 
+        (ex: /items keyword: cloth kind: item min_tier: 2 max_tier: 4)
+
+    18. /market - For server managers: make this server's market private so listings posted here are only shown here. Add include_public: True to still see the public market. Leave out the options to see the current setting. 
+        (ex: /market private: True include_public: True)
+
+    19. /help - Display this message :)
     ";
-    ctx.say(help_message).await?;
+    // Discord messages are capped at 2000 characters, so send the help in chunks of whole entries
+    let mut chunk = String::new();
+    for entry in help_message.split("\n\n") {
+        if chunk.len() + entry.len() + 2 > 2000 {
+            ctx.say(chunk).await?;
+            chunk = String::new();
+        }
+        chunk.push_str(entry);
+        chunk.push_str("\n\n");
+    }
+    ctx.say(chunk).await?;
     Ok(())
 }
 
@@ -186,13 +655,13 @@ async fn unlist(
     #[description = "listing ID"] listing_id: i32,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    let username = ctx.author().name.clone();
-    let db = Connection::open("db.db3")?;
-    let result = db.execute(
-        "DELETE FROM listings WHERE id = ? AND username = ?",
-        params![listing_id, username],
-    )?;
-    if result > 0 {
+    let user_id = ctx.author().id.get();
+    let result = ctx
+        .data()
+        .db
+        .call(move |db| ListingStore::new(db).delete(listing_id, user_id))
+        .await?;
+    if result {
         ctx.say("Listing successfully unlisted").await?;
     } else {
         ctx.say("Listing not found").await?;
@@ -200,7 +669,138 @@ async fn unlist(
     Ok(())
 }
 
+/// Renew one of your listings, or all of them if no ID is given
+#[poise::command(slash_command, prefix_command)]
+async fn renew(
+    ctx: Context<'_>,
+    #[description = "listing ID (leave empty to renew all your listings)"] listing_id: Option<i32>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let user_id = ctx.author().id.get();
+    let lifetime_days = ctx.data().listing_lifetime_days;
+    let result = ctx
+        .data()
+        .db
+        .call(move |db| ListingStore::new(db).renew(listing_id, user_id, lifetime_days))
+        .await?;
+    match (listing_id, result) {
+        (Some(_), 0) => ctx.say("Listing not found").await?,
+        (None, 0) => ctx.say("You have no listings.").await?,
+        (Some(_), _) => ctx.say("Listing successfully renewed").await?,
+        (None, count) => ctx.say(format!("Renewed {} listings", count)).await?,
+    };
+    Ok(())
+}
+
+/// Record a completed trade against one of your listings
+#[poise::command(slash_command, prefix_command)]
+async fn complete(
+    ctx: Context<'_>,
+    #[description = "listing ID"] listing_id: i32,
+    #[description = "how many times the offer was traded"] quantity: i32,
+    #[description = "who you traded with"] counterparty: Option<serenity::User>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    if quantity <= 0 {
+        ctx.say("Quantity must be at least 1").await?;
+        return Ok(());
+    }
+    let user = ctx.author().clone();
+    if counterparty.as_ref().is_some_and(|other| other.id == user.id) {
+        ctx.say("You can't trade with yourself").await?;
+        return Ok(());
+    }
+    let counterparty_id = counterparty.as_ref().map(|other| other.id.get());
+    let outcome = ctx
+        .data()
+        .db
+        .call(move |db| complete_listing(db, listing_id, user.id.get(), quantity, counterparty_id))
+        .await?;
+    let (trade, remaining) = match outcome {
+        CompleteOutcome::NotFound => {
+            ctx.say("Listing not found").await?;
+            return Ok(());
+        }
+        CompleteOutcome::InsufficientStock(stock) => {
+            ctx.say(format!("Listing only has {} in stock", stock)).await?;
+            return Ok(());
+        }
+        CompleteOutcome::Completed { trade, remaining } => (trade, remaining),
+    };
+    let summary = format!(
+        "{}x {} {} for {} {} each",
+        trade.quantity,
+        trade.offer_quantity,
+        trade.offer_item,
+        trade.request_quantity,
+        trade.request_item
+    );
+    if remaining == 0 {
+        ctx.say(format!("Trade recorded: {}. Listing {} is sold out and has been removed", summary, listing_id))
+            .await?;
+    } else {
+        ctx.say(format!("Trade recorded: {}. {} left in stock", summary, remaining))
+            .await?;
+    }
+    if let Some(counterparty) = counterparty {
+        let message = format!("{} recorded a trade with you: {}", ctx.author().name, summary);
+        if let Err(err) = send_dm(ctx.http(), counterparty.id.get(), message).await {
+            println!("Failed to send trade notice to {}: {}", counterparty.id, err);
+        }
+    }
+    Ok(())
+}
+
+/// Rate a trader you dealt with through one of their listings
+#[poise::command(slash_command, prefix_command)]
+async fn rate(
+    ctx: Context<'_>,
+    #[description = "who you traded with"] user: serenity::User,
+    #[description = "ID of their listing you traded on"] listing_id: i32,
+    #[description = "score from 1 to 5"] score: i32,
+    #[description = "comment"] comment: Option<String>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    if !SCORE_RANGE.contains(&score) {
+        ctx.say(format!(
+            "Score must be between {} and {}",
+            SCORE_RANGE.start(),
+            SCORE_RANGE.end()
+        ))
+        .await?;
+        return Ok(());
+    }
+    let rater_id = ctx.author().id.get();
+    let user_id = user.id.get();
+    let outcome = ctx
+        .data()
+        .db
+        .call(move |db| {
+            FeedbackStore::new(db).rate(
+                rater_id,
+                user_id,
+                listing_id,
+                score,
+                comment.as_deref().unwrap_or(""),
+            )
+        })
+        .await?;
+    let message = match outcome {
+        RateOutcome::Rated => format!("Thanks for rating {}!", user.name),
+        RateOutcome::ListingNotFound => "Listing not found".to_string(),
+        RateOutcome::NotOwner => format!("Listing {} doesn't belong to {}", listing_id, user.name),
+        RateOutcome::OwnListing => "You can't rate your own listing".to_string(),
+        RateOutcome::AlreadyRated => "You've already rated this listing".to_string(),
+        RateOutcome::CommentTooLong => {
+            format!("Comment must be {} characters or less", MAX_COMMENT_LENGTH)
+        }
+    };
+    ctx.say(message).await?;
+    Ok(())
+}
+
 /// Post a listing!
+#[allow(clippy::too_many_arguments)]
 #[poise::command(slash_command, prefix_command)]
 async fn list(
     ctx: Context<'_>,
@@ -212,176 +812,366 @@ async fn list(
     #[description = "request item"]
     #[autocomplete = "autocomplete_item_name"]
     request_item: String,
-    #[description = "location north"] location_north: i32,
-    #[description = "location east"] location_east: i32,
+    #[description = "saved location, instead of coordinates"]
+    #[autocomplete = "autocomplete_location"]
+    location: Option<String>,
+    #[description = "location north (default your home)"] location_north: Option<i32>,
+    #[description = "location east (default your home)"] location_east: Option<i32>,
     #[description = "offer count"] offer_count: Option<i32>,
     #[description = "description"] description: Option<String>,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    let username = ctx.author().name.clone();
-    let description = description.unwrap_or("".to_string());
-    if description.len() > 300 {
-        ctx.say("Invalid listing: Description must be 300 characters or less").await?;
-        return Ok(());
-    }
-
-    if offer_item == request_item {
-        ctx.say("Invalid listing: Offered item cannot be the same as the requested item")
-            .await?;
+    let Some((location_north, location_east, _)) =
+        command_location(ctx, location, location_north, location_east).await?
+    else {
         return Ok(());
-    }
-
-    if request_quantity == 0 || offer_quantity == 0 {
-        ctx.say("Invalid listing: Request quantity and offer quantity must be non-zero")
-            .await?;
+    };
+    let username = ctx.author().name.clone();
+    let user_id = ctx.author().id.get();
+    let offer = find_item(ctx, &offer_item).ok();
+    let request = find_item(ctx, &request_item).ok();
+    let listing = Listing {
+        id: 0,
+        offer_quantity,
+        offer_item: offer.as_ref().map_or(offer_item, |item| item.display_name()),
+        offer_item_id: offer.map(|item| item.id),
+        request_quantity,
+        request_item: request
+            .as_ref()
+            .map_or(request_item, |item| item.display_name()),
+        request_item_id: request.map(|item| item.id),
+        location_north,
+        location_east,
+        user: username.clone(),
+        user_id: Some(user_id),
+        guild_id: ctx.guild_id().map(|guild_id| guild_id.get()),
+        offer_count: offer_count.unwrap_or(1),
+        description: description.unwrap_or("".to_string()),
+        renewed_days_ago: 0,
+        distance: None,
+    };
+    if let Some(error_message) =
+        validate_listing(&ctx.data().items.get().catalog, &listing, ListingField::ALL)
+    {
+        ctx.say(error_message).await?;
         return Ok(());
     }
 
-    let db = Connection::open("db.db3")?;
-
-    let listing_count: i32 = db.query_row(
-        "SELECT COUNT(*) FROM listings WHERE username = ?",
-        params![username],
-        |row| row.get(0),
-    )?;
+    let listing_count: i32 = ctx
+        .data()
+        .db
+        .call(move |db| ListingStore::new(db).count_by_owner(user_id))
+        .await?;
 
-    if !ctx.author().name.contains("cyypherus") && listing_count >= 15 {
-        ctx.say(format!(
-            "You have reached the maximum number of listings (15). You can remove some with /my_listings & /unlist"
-        ))
+    let is_owner = ctx.framework().options().owners.contains(&ctx.author().id);
+    if !is_owner && listing_count >= 15 {
+        ctx.say(
+            "You have reached the maximum number of listings (15). You can remove some with /my_listings & /unlist",
+        )
         .await?;
         return Ok(());
     }
 
-    let offer_count = offer_count.unwrap_or(1);
-
-    if ctx.data().item_list.contains_key(&request_item)
-        && ctx.data().item_list.contains_key(&offer_item)
-    {
-        let listing_info = format_listings(
-            vec![Listing {
-                id: 0,
-                offer_quantity,
-                offer_item: offer_item.clone(),
-                request_quantity,
-                request_item: request_item.clone(),
-                location_north,
-                location_east,
-                user: username.clone(),
-                offer_count: offer_count,
-                description: description.clone(),
-            }],
-            0,
-        );
-        db.execute(
-            "INSERT INTO listings (sale_quantity, sale_item, buy_quantity, buy_item, location_north, location_east, username, timestamp, offer_count, description)
-            VALUES (?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP, ?, ?)",
-            params![
-            offer_quantity,
-            offer_item,
-            request_quantity,
-            request_item,
-            location_north,
-            location_east,
-            username,
-            offer_count,
-            description,
-            ],
-        )?;
-        println!("{}", listing_info);
-        ctx.say(format!(
-            "Listing successful! Thanks for using brt :)\n{}",
-            listing_info,
-        ))
+    let lifetime_days = ctx.data().listing_lifetime_days;
+    let match_distance = ctx.data().match_distance;
+    let (listing, matches, watches, reputations) = ctx
+        .data()
+        .db
+        .call(move |db| {
+            let listing = ListingStore::new(db).insert(listing, lifetime_days)?;
+            let matches = find_matches(db, &listing, match_distance)?;
+            let watches = WatchStore::new(db).matching(&listing)?;
+            let reputations = FeedbackStore::new(db).reputations(
+                matches.iter().chain([&listing]).filter_map(|listing| listing.user_id),
+            )?;
+            Ok((listing, matches, watches, reputations))
+        })
         .await?;
-        Ok(())
-    } else {
-        let error_message = format!("Items {} and/or {} not found.", request_item, offer_item,);
-        ctx.say(error_message).await?;
-        return Ok(());
-    }
+    let listing_info = format_listing(listing.clone(), &reputations);
+    println!("{}", listing_info);
+    ctx.say(format!(
+        "Listing successful! Thanks for using brt :)\n{}",
+        listing_info,
+    ))
+    .await?;
+    notify_matches(&ctx.serenity_context().http, &listing, matches, &reputations).await;
+    notify_watchers(&ctx.serenity_context().http, &listing, watches, &reputations).await;
+    Ok(())
 }
 
-/// Check your own listings
-#[poise::command(slash_command)]
-async fn my_listings(
+/// Edit one of your listings in place
+#[allow(clippy::too_many_arguments)]
+#[poise::command(slash_command, prefix_command)]
+async fn edit_listing(
     ctx: Context<'_>,
-    #[description = "page"] page: Option<i32>,
+    #[description = "listing ID"] listing_id: i32,
+    #[description = "offer quantity"] offer_quantity: Option<i32>,
+    #[description = "request quantity"] request_quantity: Option<i32>,
+    #[description = "offer count"] offer_count: Option<i32>,
+    #[description = "description"] description: Option<String>,
+    #[description = "location north"] location_north: Option<i32>,
+    #[description = "location east"] location_east: Option<i32>,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    let username = ctx.author().name.clone();
-    let db = Connection::open("db.db3")?;
-    let listings = query_listings_by_username(&db, &username)?;
-    if listings.is_empty() {
-        ctx.say("You have no listings.").await?;
-    } else {
-        let listings_info = format_listings(listings, page.unwrap_or(1));
-        ctx.say(listings_info).await?;
+    let user_id = ctx.author().id.get();
+    let listing = ctx
+        .data()
+        .db
+        .call(move |db| ListingStore::new(db).get(listing_id))
+        .await?;
+
+    let listing = match listing {
+        Some(listing) if listing.user_id == Some(user_id) => listing,
+        _ => {
+            ctx.say("Listing not found").await?;
+            return Ok(());
+        }
+    };
+    // Only check what's being changed, so listings stay editable after their items leave the
+    // item files
+    let edited = [
+        (offer_quantity.is_some(), ListingField::OfferQuantity),
+        (request_quantity.is_some(), ListingField::RequestQuantity),
+        (offer_count.is_some(), ListingField::OfferCount),
+        (description.is_some(), ListingField::Description),
+    ]
+    .into_iter()
+    .filter_map(|(edited, field)| edited.then_some(field))
+    .collect::<Vec<_>>();
+    let listing = Listing {
+        offer_quantity: offer_quantity.unwrap_or(listing.offer_quantity),
+        request_quantity: request_quantity.unwrap_or(listing.request_quantity),
+        offer_count: offer_count.unwrap_or(listing.offer_count),
+        description: description.unwrap_or(listing.description),
+        location_north: location_north.unwrap_or(listing.location_north),
+        location_east: location_east.unwrap_or(listing.location_east),
+        ..listing
+    };
+    if let Some(error_message) =
+        validate_listing(&ctx.data().items.get().catalog, &listing, &edited)
+    {
+        ctx.say(error_message).await?;
+        return Ok(());
     }
-    Ok(())
-}
 
-/// Query listings by username
-fn query_listings_by_username(db: &Connection, username: &str) -> Result<Vec<Listing>, Error> {
-    let mut stmt = db.prepare(
-        "SELECT id, sale_quantity, sale_item, buy_quantity, buy_item, location_north, location_east, offer_count, description
-        FROM listings
-        WHERE username = ?",
-    )?;
-    let queries = stmt.query_map(params![username], |row| {
-        Ok(Listing {
-            id: row.get(0)?,
-            offer_quantity: row.get(1)?,
-            offer_item: row.get(2)?,
-            request_quantity: row.get(3)?,
-            request_item: row.get(4)?,
-            location_north: row.get(5)?,
-            location_east: row.get(6)?,
-            user: username.to_string(),
-            offer_count: row.get(7)?,
-            description: row.get(8)?,
+    let updated = ctx
+        .data()
+        .db
+        .call(move |db| {
+            if !ListingStore::new(db).update(&listing)? {
+                return Ok(None);
+            }
+            let reputations = FeedbackStore::new(db).reputations(listing.user_id)?;
+            Ok(Some((listing, reputations)))
         })
-    })?;
-    let mut listings = Vec::<Listing>::new();
-    for q in queries {
-        listings.push(q?);
-    }
-    Ok(listings)
+        .await?;
+    let Some((listing, reputations)) = updated else {
+        ctx.say("Listing not found").await?;
+        return Ok(());
+    };
+    ctx.say(format!(
+        "Listing successfully updated\n{}",
+        format_listing(listing, &reputations),
+    ))
+    .await?;
+    Ok(())
 }
-/// Search nearby listings
+
+/// Save your home location & default search radius
 #[poise::command(slash_command, prefix_command)]
-async fn nearby_listings(
+async fn set_home(
     ctx: Context<'_>,
     #[description = "location north"] location_north: i32,
     #[description = "location east"] location_east: i32,
-    #[description = "distance"] distance: i32,
-    #[description = "page"] page: Option<i32>,
+    #[description = "default search distance (keeps your current one if left out)"]
+    radius: Option<i32>,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    // Search for listings within distance of location
-    let db = Connection::open("db.db3")?;
-
-    let rows = get_all_listings_within_distance(&db, location_north, location_east, distance)?;
-    if rows.is_empty() {
-        ctx.say(format!(
-            "No listings found within N ({} - {}) E ({} - {})",
-            location_north - distance,
-            location_north + distance,
-            location_east - distance,
-            location_east + distance,
-        ))
-        .await?;
-    } else {
-        let listings_info = format_listings(rows, page.unwrap_or(1));
-        ctx.say(format!("Nearby listings:\n{}", listings_info,))
+    if radius.is_some_and(|radius| radius <= 0) {
+        ctx.say("Radius must be greater than 0").await?;
+        return Ok(());
+    }
+    let user_id = ctx.author().id.get();
+    let home = Home {
+        location_north,
+        location_east,
+        radius,
+    };
+    let home = ctx
+        .data()
+        .db
+        .call(move |db| PreferenceStore::new(db).set_home(user_id, home))
+        .await?;
+    let radius = match home.radius {
+        Some(radius) => format!(" with a search radius of {}", radius),
+        None => "".to_string(),
+    };
+    ctx.say(format!(
+        "Home set to N:{} E:{}{}. Leave out the location or distance on /list & searches to use it",
+        home.location_north, home.location_east, radius
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Manage your saved locations
+#[poise::command(
+    slash_command,
+    subcommands("location_add", "location_remove", "location_list")
+)]
+async fn location(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Save a location under a name, to use instead of coordinates
+#[poise::command(slash_command, rename = "add")]
+async fn location_add(
+    ctx: Context<'_>,
+    #[description = "name, e.g. your claim or settlement"] name: String,
+    #[description = "location north"] location_north: i32,
+    #[description = "location east"] location_east: i32,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let name = name.trim().to_string();
+    if name.is_empty() || name.len() > 32 {
+        ctx.say("Location names must be 1 to 32 characters long")
             .await?;
+        return Ok(());
     }
+    let user_id = ctx.author().id.get();
+    let location = SavedLocation {
+        name,
+        location_north,
+        location_east,
+    };
+    let saved = ctx
+        .data()
+        .db
+        .call(move |db| {
+            let store = PreferenceStore::new(db);
+            // Autocomplete can only show 25 choices
+            if store.location(user_id, &location.name)?.is_none()
+                && store.locations(user_id)?.len() >= 25
+            {
+                return Ok(None);
+            }
+            store.add_location(user_id, &location)?;
+            Ok(Some(location))
+        })
+        .await?;
+    match saved {
+        Some(location) => {
+            ctx.say(format!(
+                "Saved {} at N:{} E:{}",
+                location.name, location.location_north, location.location_east
+            ))
+            .await?
+        }
+        None => {
+            ctx.say("You have reached the maximum number of saved locations (25). You can remove some with /location remove")
+                .await?
+        }
+    };
     Ok(())
 }
 
-/// Search nearby sellers
+/// Remove one of your saved locations
+#[poise::command(slash_command, rename = "remove")]
+async fn location_remove(
+    ctx: Context<'_>,
+    #[description = "name"]
+    #[autocomplete = "autocomplete_location"]
+    name: String,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let user_id = ctx.author().id.get();
+    let removed = ctx
+        .data()
+        .db
+        .call(move |db| PreferenceStore::new(db).remove_location(user_id, &name))
+        .await?;
+    if removed {
+        ctx.say("Location removed").await?;
+    } else {
+        ctx.say("Location not found").await?;
+    }
+    Ok(())
+}
+
+/// List your saved locations
+#[poise::command(slash_command, rename = "list")]
+async fn location_list(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let user_id = ctx.author().id.get();
+    let locations = ctx
+        .data()
+        .db
+        .call(move |db| PreferenceStore::new(db).locations(user_id))
+        .await?;
+    if locations.is_empty() {
+        ctx.say("You have no saved locations. Add one with /location add")
+            .await?;
+        return Ok(());
+    }
+    let mut message = "Your saved locations:\n".to_string();
+    for location in locations {
+        message.push_str(&format!(
+            "{}: N:{} E:{}\n",
+            location.name, location.location_north, location.location_east
+        ));
+    }
+    ctx.say(message).await?;
+    Ok(())
+}
+
+/// Make this server's market private, or open it back up
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+async fn market(
+    ctx: Context<'_>,
+    #[description = "only show listings posted here to this server"] private: Option<bool>,
+    #[description = "let members of a private market see the public market too"]
+    include_public: Option<bool>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let Some(guild_id) = ctx.guild_id() else {
+        return Ok(());
+    };
+    let guild_id = guild_id.get();
+    let settings = ctx
+        .data()
+        .db
+        .call(move |db| {
+            let store = GuildStore::new(db);
+            let current = store.settings(guild_id)?;
+            let settings = GuildSettings {
+                guild_id,
+                private_market: private.unwrap_or(current.private_market),
+                include_public: include_public.unwrap_or(current.include_public),
+            };
+            if settings != current {
+                store.set(&settings)?;
+            }
+            Ok(settings)
+        })
+        .await?;
+    let message = match settings.market() {
+        Market::Public => "This server trades in the public market".to_string(),
+        Market::Private {
+            include_public: true,
+            ..
+        } => "This server has a private market. Its listings are only shown here, and members can see the public market too".to_string(),
+        Market::Private { .. } => {
+            "This server has a private market. Its listings are only shown here".to_string()
+        }
+    };
+    ctx.say(message).await?;
+    Ok(())
+}
+
+/// Get a DM whenever someone nearby lists an item for sale
+#[allow(clippy::too_many_arguments)]
 #[poise::command(slash_command, prefix_command)]
-async fn nearby_sellers(
+async fn watch(
     ctx: Context<'_>,
     #[autocomplete = "autocomplete_item_name"]
     #[description = "item"]
@@ -389,88 +1179,759 @@ async fn nearby_sellers(
     #[description = "location north"] location_north: i32,
     #[description = "location east"] location_east: i32,
     #[description = "distance"] distance: i32,
-    #[description = "page"] page: Option<i32>,
+    #[description = "only listings asking for this item in return"]
+    #[autocomplete = "autocomplete_item_name"]
+    counter_item: Option<String>,
+    #[description = "maximum price per item, in counter item"] max_price: Option<f64>,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    // Search for listings within distance of location
-    let db = Connection::open("db.db3")?;
-
-    if !ctx.data().item_list.contains_key(&item) {
-        let error_message = format!("Item {} not found", item);
-        ctx.say(error_message).await?;
-        return Ok(());
-    }
+    let item = match find_item(ctx, &item) {
+        Ok(item) => item,
+        Err(error_message) => {
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
+    let price = match price_filter(ctx, counter_item, None, max_price) {
+        Ok(price) => price,
+        Err(error_message) => {
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
 
-    let rows = get_listings_within_distance(
-        &db,
-        &item,
+    let user_id = ctx.author().id.get();
+    let watch = Watch {
+        id: 0,
+        user_id,
+        item_id: item.id,
         location_north,
         location_east,
         distance,
-        ItemQuery::SellingItem,
-    )?;
-    if rows.is_empty() {
-        ctx.say(format!(
-            "No sellers of {} found within N ({} - {}) E ({} - {})",
-            item,
-            location_north - distance,
-            location_north + distance,
-            location_east - distance,
-            location_east + distance,
-        ))
+        counter_item_id: price.as_ref().map(|price| price.counter_item_id),
+        max_price,
+        guild_id: ctx.guild_id().map(|guild_id| guild_id.get()),
+    };
+    let watch = ctx
+        .data()
+        .db
+        .call(move |db| {
+            let store = WatchStore::new(db);
+            if store.count_by_owner(user_id)? >= 10 {
+                return Ok(None);
+            }
+            Ok(Some(store.insert(watch)?))
+        })
         .await?;
-    } else {
-        let sellers_info = format_listings(rows, page.unwrap_or(1));
-        ctx.say(format!("Nearby sellers:\n{}", sellers_info,))
+    match watch {
+        Some(watch) => {
+            ctx.say(format!(
+                "Watching for {} within N ({} - {}) E ({} - {}). You'll get a DM when one is listed.",
+                item.display_name(),
+                watch.location_north - watch.distance,
+                watch.location_north + watch.distance,
+                watch.location_east - watch.distance,
+                watch.location_east + watch.distance,
+            ))
             .await?;
+        }
+        None => {
+            ctx.say("You have reached the maximum number of watches (10). You can remove some with /unwatch")
+                .await?;
+        }
     }
     Ok(())
 }
 
-/// Search nearby buyers
+/// Stop watching an item
 #[poise::command(slash_command, prefix_command)]
-async fn nearby_buyers(
+async fn unwatch(
     ctx: Context<'_>,
     #[autocomplete = "autocomplete_item_name"]
     #[description = "item"]
     item: String,
-    #[description = "location north"] location_north: i32,
-    #[description = "location east"] location_east: i32,
-    #[description = "distance"] distance: i32,
-    #[description = "page"] page: Option<i32>,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
-    // Search for listings within distance of location
-    let db = Connection::open("db.db3")?;
+    let item = match find_item(ctx, &item) {
+        Ok(item) => item,
+        Err(error_message) => {
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
+    let user_id = ctx.author().id.get();
+    let removed = ctx
+        .data()
+        .db
+        .call(move |db| WatchStore::new(db).delete_item(user_id, item.id))
+        .await?;
+    if removed > 0 {
+        ctx.say("No longer watching that item").await?;
+    } else {
+        ctx.say("You aren't watching that item").await?;
+    }
+    Ok(())
+}
 
-    if !ctx.data().item_list.contains_key(&item) {
-        let error_message = format!("Item {} not found", item);
-        ctx.say(error_message).await?;
-        return Ok(());
+/// Check a listing is well formed, returning a message for the user if it isn't
+fn validate_listing(
+    catalog: &ItemCatalog,
+    listing: &Listing,
+    fields: &[ListingField],
+) -> Option<String> {
+    if let Err(error_message) = listing.validate(fields) {
+        return Some(error_message);
     }
+    let in_catalog = |item_id: Option<i64>| item_id.is_some_and(|id| catalog.get(id).is_some());
+    if fields.contains(&ListingField::Items)
+        && !(in_catalog(listing.request_item_id) && in_catalog(listing.offer_item_id))
+    {
+        return Some(format!(
+            "Items {} and/or {} not found.",
+            listing.request_item, listing.offer_item,
+        ));
+    }
+    None
+}
 
-    let rows = get_listings_within_distance(
-        &db,
-        &item,
+/// Check your own listings
+#[poise::command(slash_command)]
+async fn my_listings(
+    ctx: Context<'_>,
+    #[description = "display style (default embed)"] style: Option<DisplayStyle>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let user_id = ctx.author().id.get();
+    let (listings, reputations) = ctx
+        .data()
+        .db
+        .call(move |db| with_reputations(db, ListingStore::new(db).by_owner(user_id)?))
+        .await?;
+    if listings.is_empty() {
+        ctx.say("You have no listings.").await?;
+    } else {
+        send_pages(ctx, "", render_listings(style.unwrap_or_default(), listings, &reputations)).await?;
+    }
+    Ok(())
+}
+
+/// Search nearby listings
+#[allow(clippy::too_many_arguments)]
+#[poise::command(slash_command, prefix_command)]
+async fn nearby_listings(
+    ctx: Context<'_>,
+    #[description = "saved location, instead of coordinates"]
+    #[autocomplete = "autocomplete_location"]
+    location: Option<String>,
+    #[description = "location north (default your home)"] location_north: Option<i32>,
+    #[description = "location east (default your home)"] location_east: Option<i32>,
+    #[description = "distance (default your home radius)"] distance: Option<i32>,
+    #[description = "distance metric (default square)"] metric: Option<DistanceMetric>,
+    #[description = "minimum distance for the ring metric"] min_distance: Option<i32>,
+    #[description = "only listings renewed within this many days"] max_age_days: Option<i32>,
+    #[description = "sort results by (default distance)"] sort: Option<SortOrder>,
+    #[description = "display style (default embed)"] style: Option<DisplayStyle>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let Some((location_north, location_east, radius)) =
+        command_location(ctx, location, location_north, location_east).await?
+    else {
+        return Ok(());
+    };
+    let Some(distance) = distance.or(radius) else {
+        ctx.say("Add a distance, or save a default radius with /set_home")
+            .await?;
+        return Ok(());
+    };
+    // Search for listings within distance of location
+    let search = ListingSearch {
         location_north,
         location_east,
         distance,
-        ItemQuery::BuyingItem,
-    )?;
+        metric: metric.unwrap_or_default(),
+        min_distance,
+        item: None,
+        max_age_days,
+        price: None,
+        sort: sort.unwrap_or_default(),
+        market: current_market(ctx).await?,
+    };
+    let area = describe_area(&search);
+    let (rows, reputations) = ctx
+        .data()
+        .db
+        .call(move |db| with_reputations(db, ListingStore::new(db).search(&search)?))
+        .await?;
     if rows.is_empty() {
-        ctx.say(format!(
-            "No buyers of {} found within N ({} - {}) E ({} - {}).",
-            item,
-            location_north - distance,
-            location_north + distance,
-            location_east - distance,
-            location_east + distance,
-        ))
+        ctx.say(format!("No listings found {}", area)).await?;
+    } else {
+        let pages = render_listings(style.unwrap_or_default(), rows, &reputations);
+        send_pages(ctx, "Nearby listings:\n", pages).await?;
+    }
+    Ok(())
+}
+
+/// Search nearby sellers
+#[allow(clippy::too_many_arguments)]
+#[poise::command(slash_command)]
+async fn nearby_sellers(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_item_name"]
+    #[description = "item, or its name without a tier to search every tier"]
+    item: String,
+    #[description = "lowest tier to search, searches every tier of the item"] min_tier: Option<i32>,
+    #[description = "highest tier to search, searches every tier of the item"] max_tier: Option<i32>,
+    #[description = "saved location, instead of coordinates"]
+    #[autocomplete = "autocomplete_location"]
+    location: Option<String>,
+    #[description = "location north (default your home)"] location_north: Option<i32>,
+    #[description = "location east (default your home)"] location_east: Option<i32>,
+    #[description = "distance (default your home radius)"] distance: Option<i32>,
+    #[description = "distance metric (default square)"] metric: Option<DistanceMetric>,
+    #[description = "minimum distance for the ring metric"] min_distance: Option<i32>,
+    #[description = "only listings renewed within this many days"] max_age_days: Option<i32>,
+    #[description = "item prices are compared in"]
+    #[autocomplete = "autocomplete_item_name"]
+    counter_item: Option<String>,
+    #[description = "minimum price per item, in counter item"] min_price: Option<f64>,
+    #[description = "maximum price per item, in counter item"] max_price: Option<f64>,
+    #[description = "sort results by (default distance)"] sort: Option<SortOrder>,
+    #[description = "display style (default embed)"] style: Option<DisplayStyle>,
+) -> Result<(), Error> {
+    let search = TraderSearch {
+        item,
+        min_tier,
+        max_tier,
+        location,
+        location_north,
+        location_east,
+        distance,
+        metric,
+        min_distance,
+        max_age_days,
+        counter_item,
+        min_price,
+        max_price,
+        sort,
+        style,
+    };
+    search_traders(ctx, ItemQuery::SellingItem, search).await
+}
+
+/// Prefix form of /nearby_sellers, merged into it when the commands are registered. It takes the
+/// core options only, as poise generates prefix parsing code for every combination of optional
+/// arguments & all 14 of the slash options don't compile in reasonable memory
+#[allow(clippy::too_many_arguments)]
+#[poise::command(prefix_command)]
+async fn nearby_sellers_prefix(
+    ctx: Context<'_>,
+    item: String,
+    location_north: Option<i32>,
+    location_east: Option<i32>,
+    distance: Option<i32>,
+    counter_item: Option<String>,
+    min_price: Option<f64>,
+    max_price: Option<f64>,
+    sort: Option<SortOrder>,
+) -> Result<(), Error> {
+    let search = TraderSearch {
+        item,
+        location_north,
+        location_east,
+        distance,
+        counter_item,
+        min_price,
+        max_price,
+        sort,
+        ..Default::default()
+    };
+    search_traders(ctx, ItemQuery::SellingItem, search).await
+}
+
+/// Search nearby buyers
+#[allow(clippy::too_many_arguments)]
+#[poise::command(slash_command)]
+async fn nearby_buyers(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_item_name"]
+    #[description = "item, or its name without a tier to search every tier"]
+    item: String,
+    #[description = "lowest tier to search, searches every tier of the item"] min_tier: Option<i32>,
+    #[description = "highest tier to search, searches every tier of the item"] max_tier: Option<i32>,
+    #[description = "saved location, instead of coordinates"]
+    #[autocomplete = "autocomplete_location"]
+    location: Option<String>,
+    #[description = "location north (default your home)"] location_north: Option<i32>,
+    #[description = "location east (default your home)"] location_east: Option<i32>,
+    #[description = "distance (default your home radius)"] distance: Option<i32>,
+    #[description = "distance metric (default square)"] metric: Option<DistanceMetric>,
+    #[description = "minimum distance for the ring metric"] min_distance: Option<i32>,
+    #[description = "only listings renewed within this many days"] max_age_days: Option<i32>,
+    #[description = "item prices are compared in"]
+    #[autocomplete = "autocomplete_item_name"]
+    counter_item: Option<String>,
+    #[description = "minimum price per item, in counter item"] min_price: Option<f64>,
+    #[description = "maximum price per item, in counter item"] max_price: Option<f64>,
+    #[description = "sort results by (default distance)"] sort: Option<SortOrder>,
+    #[description = "display style (default embed)"] style: Option<DisplayStyle>,
+) -> Result<(), Error> {
+    let search = TraderSearch {
+        item,
+        min_tier,
+        max_tier,
+        location,
+        location_north,
+        location_east,
+        distance,
+        metric,
+        min_distance,
+        max_age_days,
+        counter_item,
+        min_price,
+        max_price,
+        sort,
+        style,
+    };
+    search_traders(ctx, ItemQuery::BuyingItem, search).await
+}
+
+/// Prefix form of /nearby_buyers, with the same core options as `nearby_sellers_prefix`
+#[allow(clippy::too_many_arguments)]
+#[poise::command(prefix_command)]
+async fn nearby_buyers_prefix(
+    ctx: Context<'_>,
+    item: String,
+    location_north: Option<i32>,
+    location_east: Option<i32>,
+    distance: Option<i32>,
+    counter_item: Option<String>,
+    min_price: Option<f64>,
+    max_price: Option<f64>,
+    sort: Option<SortOrder>,
+) -> Result<(), Error> {
+    let search = TraderSearch {
+        item,
+        location_north,
+        location_east,
+        distance,
+        counter_item,
+        min_price,
+        max_price,
+        sort,
+        ..Default::default()
+    };
+    search_traders(ctx, ItemQuery::BuyingItem, search).await
+}
+
+/// Options of a seller or buyer search
+#[derive(Default)]
+struct TraderSearch {
+    item: String,
+    min_tier: Option<i32>,
+    max_tier: Option<i32>,
+    location: Option<String>,
+    location_north: Option<i32>,
+    location_east: Option<i32>,
+    distance: Option<i32>,
+    metric: Option<DistanceMetric>,
+    min_distance: Option<i32>,
+    max_age_days: Option<i32>,
+    counter_item: Option<String>,
+    min_price: Option<f64>,
+    max_price: Option<f64>,
+    sort: Option<SortOrder>,
+    style: Option<DisplayStyle>,
+}
+
+/// Search nearby listings selling or buying an item
+async fn search_traders(
+    ctx: Context<'_>,
+    query: ItemQuery,
+    search: TraderSearch,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let TraderSearch {
+        item,
+        min_tier,
+        max_tier,
+        location,
+        location_north,
+        location_east,
+        distance,
+        metric,
+        min_distance,
+        max_age_days,
+        counter_item,
+        min_price,
+        max_price,
+        sort,
+        style,
+    } = search;
+    let items = match tiered_items(ctx, &item, min_tier, max_tier) {
+        Ok(items) => items,
+        Err(error_message) => {
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
+    let price = match price_filter(ctx, counter_item, min_price, max_price) {
+        Ok(price) => price,
+        Err(error_message) => {
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
+    let Some((location_north, location_east, radius)) =
+        command_location(ctx, location, location_north, location_east).await?
+    else {
+        return Ok(());
+    };
+    let Some(distance) = distance.or(radius) else {
+        ctx.say("Add a distance, or save a default radius with /set_home")
+            .await?;
+        return Ok(());
+    };
+
+    let search = ListingSearch {
+        location_north,
+        location_east,
+        distance,
+        metric: metric.unwrap_or_default(),
+        min_distance,
+        item: None,
+        max_age_days,
+        price,
+        sort: sort.unwrap_or_default(),
+        market: current_market(ctx).await?,
+    };
+    let area = describe_area(&search);
+    let grouped = items.len() > 1;
+    let (groups, reputations) = ctx
+        .data()
+        .db
+        .call(move |db| search_groups(db, &search, items, query))
         .await?;
+    let noun = match query {
+        ItemQuery::SellingItem => "sellers",
+        ItemQuery::BuyingItem => "buyers",
+    };
+    if groups.is_empty() {
+        ctx.say(format!("No {} of {} found {}", noun, item, area))
+            .await?;
     } else {
-        let buyers_info = format_listings(rows, page.unwrap_or(1));
-        ctx.say(format!("Nearby buyers:\n{}", buyers_info,)).await?;
+        let style = style.unwrap_or_default();
+        let pages = if grouped {
+            render_groups(style, groups, &reputations)
+        } else {
+            let rows = groups.into_iter().flat_map(|(_, rows)| rows).collect();
+            render_listings(style, rows, &reputations)
+        };
+        send_pages(ctx, &format!("Nearby {}:\n", noun), pages).await?;
+    }
+    Ok(())
+}
+
+/// The market of the guild a command was used in
+async fn current_market(ctx: Context<'_>) -> Result<Market, Error> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return Ok(Market::Public);
+    };
+    let guild_id = guild_id.get();
+    ctx.data()
+        .db
+        .call(move |db| Ok(GuildStore::new(db).settings(guild_id)?.market()))
+        .await
+}
+
+/// Work out where a command is about from its coordinates, a saved location or the user's home,
+/// telling the user if it can't. Returns the location & the user's default search radius
+async fn command_location(
+    ctx: Context<'_>,
+    location: Option<String>,
+    location_north: Option<i32>,
+    location_east: Option<i32>,
+) -> Result<Option<(i32, i32, Option<i32>)>, Error> {
+    let user_id = ctx.author().id.get();
+    let (home, saved) = ctx
+        .data()
+        .db
+        .call(move |db| {
+            let store = PreferenceStore::new(db);
+            let saved = match location {
+                Some(name) => Some((store.location(user_id, &name)?, name)),
+                None => None,
+            };
+            Ok((store.home(user_id)?, saved))
+        })
+        .await?;
+    let radius = home.and_then(|home| home.radius);
+    let error_message = match (location_north, location_east, saved, home) {
+        (Some(north), Some(east), _, _) => return Ok(Some((north, east, radius))),
+        (None, None, Some((Some(saved), _)), _) => {
+            return Ok(Some((saved.location_north, saved.location_east, radius)))
+        }
+        (None, None, None, Some(home)) => {
+            return Ok(Some((home.location_north, home.location_east, radius)))
+        }
+        (None, None, Some((None, name)), _) => format!(
+            "You have no saved location called {}. See /location list",
+            name
+        ),
+        (None, None, None, None) => {
+            "Add location_north & location_east or a saved location, or save your home with /set_home"
+                .to_string()
+        }
+        _ => "Add both location_north & location_east, or leave both out".to_string(),
+    };
+    ctx.say(error_message).await?;
+    Ok(None)
+}
+
+/// Look up an item by name or alias, returning it or a message for the user
+fn find_item(ctx: Context<'_>, name: &str) -> Result<CatalogItem, String> {
+    ctx.data()
+        .items
+        .get()
+        .catalog
+        .find(name)
+        .cloned()
+        .ok_or_else(|| format!("Item {} not found", name))
+}
+
+/// The items a seller or buyer search covers: the item given, or every tier of it when a tier
+/// range is given or the name has no tier. Returns a message for the user if there are none
+fn tiered_items(
+    ctx: Context<'_>,
+    name: &str,
+    min_tier: Option<i32>,
+    max_tier: Option<i32>,
+) -> Result<Vec<CatalogItem>, String> {
+    let items = ctx.data().items.get();
+    let catalog = &items.catalog;
+    let found = catalog.find(name);
+    if let (Some(item), None, None) = (found, min_tier, max_tier) {
+        return Ok(vec![item.clone()]);
+    }
+    let base_name = found.map_or(name, |item| item.name.as_str());
+    let tiers = catalog.tiers(base_name, min_tier, max_tier);
+    if tiers.is_empty() {
+        return Err(match (min_tier, max_tier) {
+            (None, None) => format!("Item {} not found", name),
+            _ => format!("No tiers of {} found in that range", base_name),
+        });
+    }
+    Ok(tiers.into_iter().cloned().collect())
+}
+
+/// Search for listings trading each of `items`, skipping items with no results. Returns the
+/// results of each item & the ratings of their owners
+fn search_groups(
+    db: &rusqlite::Connection,
+    search: &ListingSearch,
+    items: Vec<CatalogItem>,
+    query: ItemQuery,
+) -> Result<(Vec<ListingGroup>, HashMap<u64, Reputation>), Error> {
+    let store = ListingStore::new(db);
+    let mut groups = Vec::new();
+    for item in items {
+        let listings = store.search(&ListingSearch {
+            item: Some((item.id, query)),
+            ..search.clone()
+        })?;
+        if !listings.is_empty() {
+            groups.push((item.display_name(), listings));
+        }
+    }
+    let reputations = FeedbackStore::new(db).reputations(
+        groups
+            .iter()
+            .flat_map(|(_, listings)| listings)
+            .filter_map(|listing| listing.user_id),
+    )?;
+    Ok((groups, reputations))
+}
+
+/// Build the price filter for a seller or buyer search, returning a message for the user if the
+/// options don't make sense together
+fn price_filter(
+    ctx: Context<'_>,
+    counter_item: Option<String>,
+    min_price: Option<f64>,
+    max_price: Option<f64>,
+) -> Result<Option<PriceFilter>, String> {
+    let Some(counter_item) = counter_item else {
+        if min_price.is_some() || max_price.is_some() {
+            return Err("Choose a counter_item to filter by price".to_string());
+        }
+        return Ok(None);
+    };
+    Ok(Some(PriceFilter {
+        counter_item_id: find_item(ctx, &counter_item)?.id,
+        min_price,
+        max_price,
+    }))
+}
+
+/// Describe the area a search covers, e.g. "within N (900 - 1100) E (900 - 1100)"
+fn describe_area(search: &ListingSearch) -> String {
+    match search.metric {
+        DistanceMetric::Square => format!(
+            "within N ({} - {}) E ({} - {})",
+            search.location_north - search.distance,
+            search.location_north + search.distance,
+            search.location_east - search.distance,
+            search.location_east + search.distance,
+        ),
+        DistanceMetric::Circle => format!(
+            "within {} of N:{} E:{}",
+            search.distance, search.location_north, search.location_east,
+        ),
+        DistanceMetric::Ring => format!(
+            "between {} and {} from N:{} E:{}",
+            search.min_distance.unwrap_or(0),
+            search.distance,
+            search.location_north,
+            search.location_east,
+        ),
+    }
+}
+
+/// Browse the item catalog
+#[poise::command(slash_command)]
+async fn items(
+    ctx: Context<'_>,
+    #[description = "part of the item's name"] keyword: Option<String>,
+    #[description = "cargo or item"] kind: Option<ItemKind>,
+    #[description = "lowest tier"] min_tier: Option<i32>,
+    #[description = "highest tier"] max_tier: Option<i32>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let market = current_market(ctx).await?;
+    let counts = ctx
+        .data()
+        .db
+        .call(move |db| ListingStore::new(db).count_by_item(market))
+        .await?;
+    let filter = ItemFilter {
+        kind,
+        min_tier,
+        max_tier,
+        keyword,
+    };
+    let loaded = ctx.data().items.get();
+    let items = loaded.catalog.browse(&filter);
+    if items.is_empty() {
+        ctx.say("No items found.").await?;
+        return Ok(());
+    }
+    let header = format!("{} items:\n", items.len());
+    let pages = format_items(&items, &counts)
+        .into_iter()
+        .map(|table| Page {
+            heading: "".to_string(),
+            body: PageBody::Table(table),
+        })
+        .collect();
+    send_pages(ctx, &header, pages).await?;
+    Ok(())
+}
+
+/// Reload the item files without restarting
+#[poise::command(slash_command, owners_only)]
+async fn reload_items(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let report = reload_item_files(&ctx.data().db, &ctx.data().items).await?;
+    ctx.say(report).await?;
+    Ok(())
+}
+
+/// Check what an item has been trading for
+#[poise::command(slash_command, prefix_command)]
+async fn price(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_item_name"]
+    #[description = "item"]
+    item: String,
+    #[description = "item the price is given in (leave empty for the most common ones)"]
+    #[autocomplete = "autocomplete_item_name"]
+    counter_item: Option<String>,
+) -> Result<(), Error> {
+    ctx.defer_ephemeral().await?;
+    let found = find_item(ctx, &item).and_then(|item| {
+        let counter_item = counter_item
+            .map(|counter_item| find_item(ctx, &counter_item))
+            .transpose()?;
+        Ok((item, counter_item))
+    });
+    let (item, counter_item) = match found {
+        Ok(found) => found,
+        Err(error_message) => {
+            ctx.say(error_message).await?;
+            return Ok(());
+        }
+    };
+
+    let market = current_market(ctx).await?;
+    let loaded = ctx.data().items.get();
+    let item_id = item.id;
+    let report = ctx
+        .data()
+        .db
+        .call(move |db| {
+            let history = HistoryStore::new(db);
+            let counter_items = match counter_item {
+                Some(counter_item) => vec![counter_item],
+                // Items that have left the catalog have no name to show
+                None => history
+                    .counter_items(item_id, 30, market)?
+                    .into_iter()
+                    .filter_map(|(counter_item_id, _)| loaded.catalog.get(counter_item_id))
+                    .take(3)
+                    .cloned()
+                    .collect(),
+            };
+            let mut report = Vec::<(String, Vec<(&str, Option<PriceStats>)>)>::new();
+            for counter_item in counter_items {
+                let mut periods = Vec::new();
+                for (period, days) in [("24h", 1), ("7d", 7), ("30d", 30)] {
+                    let prices = history.prices(item_id, counter_item.id, days, market)?;
+                    periods.push((period, PriceStats::from_prices(prices)));
+                }
+                report.push((counter_item.display_name(), periods));
+            }
+            Ok(report)
+        })
+        .await?;
+
+    let item = item.display_name();
+    if report.is_empty() {
+        ctx.say(format!("No listings for {} in the last 30 days", item))
+            .await?;
+        return Ok(());
     }
+    let mut message = String::new();
+    for (counter_item, periods) in report {
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_CLEAN);
+        table.add_row(row!["Period", "Median", "Min", "Max", "Listings"]);
+        for (period, stats) in periods {
+            match stats {
+                Some(stats) => table.add_row(row![
+                    period,
+                    format_price(stats.median),
+                    format_price(stats.min),
+                    format_price(stats.max),
+                    stats.count
+                ]),
+                None => table.add_row(row![period, "-", "-", "-", 0]),
+            };
+        }
+        message.push_str(&format!(
+            "{} per {}:\n```\n{}\n```\n",
+            counter_item, item, table
+        ));
+    }
+    ctx.say(message).await?;
     Ok(())
 }
 
@@ -479,25 +1940,56 @@ async fn nearby_buyers(
 async fn info(
     ctx: Context<'_>,
     #[description = "listing ID"] listing_id: i32,
+    #[description = "display style (default embed)"] style: Option<DisplayStyle>,
 ) -> Result<(), Error> {
     ctx.defer_ephemeral().await?;
     
-    let db = Connection::open("db.db3")?;
-    let listing = get_listing_by_id(&db, listing_id)?;
+    let market = current_market(ctx).await?;
+    let (listing, reputations) = ctx
+        .data()
+        .db
+        .call(move |db| {
+            let listing = ListingStore::new(db).get_in(listing_id, market)?;
+            let reputations = FeedbackStore::new(db)
+                .reputations(listing.as_ref().and_then(|listing| listing.user_id))?;
+            Ok((listing, reputations))
+        })
+        .await?;
     
-    if let Some(listing) = listing {
+    if let Some(mut listing) = listing {
+        // Show the owner's current name rather than the one they listed under
+        if let Some(user_id) = listing.user_id {
+            if let Ok(user) = serenity::UserId::new(user_id).to_user(ctx).await {
+                listing.user = user.name;
+            }
+        }
+        if style.unwrap_or_default() == DisplayStyle::Embed {
+            ctx.send(poise::CreateReply::default().embed(listing_embed(&listing, &reputations)))
+                .await?;
+            return Ok(());
+        }
         let mut info = format!("```Description: {}\n", listing.description);
         info.push_str(&format!(
-            "Offer: {} {}\nRequest: {} {}\nLocation: N:{} E:{}\nStock: {}{}\n",
+            "Offer: {} {}\nRequest: {} {}\nUnit price: {} {} per {}\nLocation: N:{} E:{}\nStock: {}\nLast renewed: {}{}\n",
             listing.offer_quantity,
             listing.offer_item,
             listing.request_quantity,
             listing.request_item,
+            format_price(listing.unit_price()),
+            listing.request_item,
+            listing.offer_item,
             listing.location_north,
             listing.location_east,
             listing.offer_count,
+            format_renewed(listing.renewed_days_ago),
             if listing.user != "cyypherus" {
-                format!("\nUser: {}\n", listing.user)
+                match listing.user_id.and_then(|user_id| reputations.get(&user_id)) {
+                    Some(reputation) => format!(
+                        "\nUser: {} (rated {:.1}/5 by {} traders)\n",
+                        listing.user, reputation.average, reputation.count
+                    ),
+                    None => format!("\nUser: {}\n", listing.user),
+                }
             } else {
                 "".to_string()
             },
@@ -511,209 +2003,344 @@ async fn info(
     Ok(())
 }
 
-fn load_items_from_file(file_name: &str) -> Result<Vec<Item>, Error> {
-    let mut items = Vec::<Item>::new();
-    let mut cargo_file = File::open(file_name)?;
-    let mut cargo_data_string = String::new();
-    cargo_file.read_to_string(&mut cargo_data_string)?;
-    let mut rdr = ReaderBuilder::new()
-        .delimiter(b'|')
-        .quoting(false)
-        .has_headers(true)
-        .from_reader(cargo_data_string.as_bytes());
-    rdr.records().for_each(|result| {
-        let record = result.expect("Failed to parse record");
-        let item = Item {
-            name: record[0].trim().to_string(),
-            tier: record[1].trim().parse().expect("Failed to parse tier"),
-        };
-        items.push(item);
-    });
-    Ok(items)
-}
-
-/// Get listings within a certain distance of a location
-fn get_all_listings_within_distance(
-    db: &Connection,
-    location_north: i32,
-    location_east: i32,
-    distance: i32,
-) -> Result<Vec<Listing>, Error> {
-    let mut stmt = db.prepare(
-        "SELECT id, sale_quantity, sale_item, buy_quantity, buy_item, location_north, location_east, username, offer_count, description
-        FROM listings
-        WHERE
-        ABS(location_north - (?1)) <= (?2) AND ABS(location_east - (?3)) <= (?4)",
-    )?;
-    let queries = stmt.query_map(
-        params![location_north, distance, location_east, distance,],
-        |row| {
-            Ok(Listing {
-                id: row.get(0)?,
-                offer_quantity: row.get(1)?,
-                offer_item: row.get(2)?,
-                request_quantity: row.get(3)?,
-                request_item: row.get(4)?,
-                location_north: row.get(5)?,
-                location_east: row.get(6)?,
-                user: row.get(7)?,
-                offer_count: row.get(8)?,
-                description: row.get(9)?,
-            })
-        },
-    )?;
-    let mut local_rows = Vec::<Listing>::new();
-    for q in queries {
-        local_rows.push(q?);
-    }
-    Ok(local_rows)
-}
-
-/// Get listings within a certain distance of a location
-fn get_listings_within_distance(
-    db: &Connection,
-    item: &str,
-    location_north: i32,
-    location_east: i32,
-    distance: i32,
-    listing_type: ItemQuery,
-) -> Result<Vec<Listing>, Error> {
-    let (search_buy_item, search_sell_item) = match listing_type {
-        ItemQuery::BuyingItem => (true, false),
-        ItemQuery::SellingItem => (false, true),
-    };
-    let mut stmt = db.prepare(
-        "SELECT id, sale_quantity, sale_item, buy_quantity, buy_item, location_north, location_east, username, offer_count, description
-        FROM listings
-        WHERE
-        ((buy_item = ?5 AND ?6) OR (sale_item = ?5 AND ?7))
-        AND ABS(location_north - (?1)) <= (?2) AND ABS(location_east - (?3)) <= (?4)",
-    )?;
-    let queries = stmt.query_map(
-        params![
-            location_north,
-            distance,
-            location_east,
-            distance,
-            item,
-            search_buy_item,
-            search_sell_item,
-        ],
-        |row| {
-            Ok(Listing {
-                id: row.get(0)?,
-                offer_quantity: row.get(1)?,
-                offer_item: row.get(2)?,
-                request_quantity: row.get(3)?,
-                request_item: row.get(4)?,
-                location_north: row.get(5)?,
-                location_east: row.get(6)?,
-                user: row.get(7)?,
-                offer_count: row.get(8)?,
-                description: row.get(9)?,
-            })
-        },
-    )?;
-    let mut local_rows = Vec::<Listing>::new();
-    for q in queries {
-        local_rows.push(q?);
-    }
-    Ok(local_rows)
+fn load_items_from_file(file_name: &str, kind: ItemKind) -> Result<Vec<ItemDefinition>, Error> {
+    let mut data = String::new();
+    File::open(file_name)?.read_to_string(&mut data)?;
+    parse_item_file(&data, kind)
 }
 
 async fn autocomplete_item_name<'a>(
     ctx: Context<'_>,
     partial: &'a str,
 ) -> impl Stream<Item = String> + 'a {
-    let lowercased = partial.to_lowercase();
-    let finder = memmem::Finder::new(lowercased.as_str());
-    let mut item_list = ctx
+    let items = ctx
         .data()
-        .item_list
-        .keys()
-        .cloned()
-        .filter(|item| {
-            finder
-                .find_iter(item.to_lowercase().as_bytes())
-                .next()
-                .is_some()
-        })
+        .items
+        .get()
+        .index
+        .search(partial, 15)
+        .into_iter()
+        .map(str::to_string)
         .collect::<Vec<String>>();
-    item_list.truncate(15);
-    futures::stream::iter(item_list)
+    futures::stream::iter(items)
 }
 
-/// Get a listing by ID
-fn get_listing_by_id(db: &Connection, listing_id: i32) -> Result<Option<Listing>, Error> {
-    let mut stmt = db.prepare(
-        "SELECT id, sale_quantity, sale_item, buy_quantity, buy_item, location_north, location_east, username, offer_count, description
-        FROM listings
-        WHERE id = ?1",
-    )?;
-    let query = stmt.query_row(params![listing_id], |row| {
-        Ok(Listing {
-            id: row.get(0)?,
-            offer_quantity: row.get(1)?,
-            offer_item: row.get(2)?,
-            request_quantity: row.get(3)?,
-            request_item: row.get(4)?,
-            location_north: row.get(5)?,
-            location_east: row.get(6)?,
-            user: row.get(7)?,
-            offer_count: row.get(8)?,
-            description: row.get(9)?,
-        })
-    });
-    
-    match query {
-        Ok(listing) => Ok(Some(listing)),
-        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-        Err(err) => Err(Box::new(err)),
+async fn autocomplete_location<'a>(
+    ctx: Context<'_>,
+    partial: &'a str,
+) -> impl Stream<Item = String> + 'a {
+    let user_id = ctx.author().id.get();
+    let locations = ctx
+        .data()
+        .db
+        .call(move |db| PreferenceStore::new(db).locations(user_id))
+        .await
+        .unwrap_or_default();
+    let lowercased = partial.to_lowercase();
+    futures::stream::iter(
+        locations
+            .into_iter()
+            .map(|location| location.name)
+            .filter(move |name| name.to_lowercase().contains(&lowercased)),
+    )
+}
+
+/// Split rows into tables that fit in a message, each page starting with the header row
+fn paginate_table(header: Row, rows: impl IntoIterator<Item = Row>) -> Vec<String> {
+    let new_table = || {
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_CLEAN);
+        table.add_row(header.clone());
+        table
+    };
+    let mut table = new_table();
+    let mut pages = Vec::<String>::new();
+    for row in rows {
+        table.add_row(row.clone());
+        // Leave room for the header sent with each page
+        if table.len() > 2 && table.to_string().len() > 1900 {
+            table.remove_row(table.len() - 1);
+            pages.push(format!("```\n{}\n```", table));
+            table = new_table();
+            table.add_row(row);
+        }
     }
+    pages.push(format!("```\n{}\n```", table));
+    pages
 }
 
-/// Format a vector of listings into a string for display
-fn format_listings(listings: Vec<Listing>, page: i32) -> String {
-    let user_page = page;
-    let page = page - 1;
-    let mut i = 0;
-    let mut table = Table::new();
-    table.set_format(*format::consts::FORMAT_CLEAN);
-    table.add_row(row![
+/// Format a vector of listings into tables, split into pages that fit in a message
+fn format_listings(listings: Vec<Listing>, reputations: &HashMap<u64, Reputation>) -> Vec<String> {
+    let header = row![
         "Offer",
         "Request",
         "Location",
+        "Each",
+        "Renewed",
+        "User",
         "ID"
-    ]);
-    let mut pages = Vec::<String>::new();
-    for listing in listings {
-        let row = row![
+    ];
+    let rows = listings.into_iter().map(|listing| {
+        row![
             format!("{} {}", listing.offer_quantity, listing.offer_item),
             format!("{} {}", listing.request_quantity, listing.request_item),
-            format!("N:{} E:{}", listing.location_north, listing.location_east),
+            match listing.distance {
+                Some(distance) => format!(
+                    "N:{} E:{} ({:.0} away)",
+                    listing.location_north, listing.location_east, distance
+                ),
+                None => format!("N:{} E:{}", listing.location_north, listing.location_east),
+            },
+            format_price(listing.unit_price()),
+            format_renewed(listing.renewed_days_ago),
+            format_user(&listing, reputations),
             listing.id
-        ];
-        table.add_row(row.clone());
-        i += 1;
-        if table.to_string().len() > 2000 {
-            let removed_1 = table.get_row(i).unwrap().clone();
-            let removed_2 = table.get_row(i - 1).unwrap().clone();
-            table.remove_row(i);
-            table.remove_row(i - 1);
-            table.add_row(row![format!("Show more... add page:{} to your command", user_page + 1)]);
-            pages.push(format!("```\n{}\n```", table.to_string()));
-            table = Table::new();
-            table.set_format(*format::consts::FORMAT_CLEAN);
-            table.add_row(row![
-                "Offer",
-                "Request",
-                "Location",
-                "ID"
-            ]);
-            table.add_row(removed_2);
-            table.add_row(removed_1);
-        }
-    }
-    pages.push(format!("```\n{}\n```", table.to_string()));
-    pages[(page as usize).min(pages.len() - 1)].clone()
-}
\ No newline at end of file
+        ]
+    });
+    paginate_table(header, rows)
+}
+
+/// Format catalog items as tables that fit in a message, with how many listings trade each
+fn format_items(items: &[&CatalogItem], counts: &HashMap<i64, i32>) -> Vec<String> {
+    let rows = items.iter().map(|item| {
+        row![
+            item.display_name(),
+            item.kind.as_str(),
+            counts.get(&item.id).copied().unwrap_or(0)
+        ]
+    });
+    paginate_table(row!["Item", "Kind", "Listings"], rows)
+}
+
+/// Format a single listing as a table
+fn format_listing(listing: Listing, reputations: &HashMap<u64, Reputation>) -> String {
+    format_listings(vec![listing], reputations).concat()
+}
+
+/// Send pages of results, with Previous & Next buttons to flip through them if there's more than
+/// one. The pages stay cached in the message for PAGE_TIMEOUT after the last button press
+async fn send_pages(ctx: Context<'_>, header: &str, pages: Vec<Page>) -> Result<(), Error> {
+    if pages.len() == 1 {
+        ctx.send(pages[0].reply(header)).await?;
+        return Ok(());
+    }
+    // Button IDs start with the context ID to tell this command's presses apart from others
+    let ctx_id = ctx.id();
+    let prev_button_id = format!("{}prev", ctx_id);
+    let next_button_id = format!("{}next", ctx_id);
+    let page_button_id = format!("{}page", ctx_id);
+    let buttons = |page: usize| {
+        vec![serenity::CreateActionRow::Buttons(vec![
+            serenity::CreateButton::new(&prev_button_id)
+                .label("Previous")
+                .disabled(page == 0),
+            serenity::CreateButton::new(&page_button_id)
+                .label(format!("Page {}/{}", page + 1, pages.len()))
+                .style(serenity::ButtonStyle::Secondary)
+                .disabled(true),
+            serenity::CreateButton::new(&next_button_id)
+                .label("Next")
+                .disabled(page + 1 == pages.len()),
+        ])]
+    };
+
+    let mut page = 0;
+    let reply = ctx
+        .send(
+            pages[page].reply(header).components(buttons(page)),
+        )
+        .await?;
+    while let Some(press) = serenity::ComponentInteractionCollector::new(ctx)
+        .filter(move |press| press.data.custom_id.starts_with(&ctx_id.to_string()))
+        .timeout(PAGE_TIMEOUT)
+        .await
+    {
+        if press.data.custom_id == next_button_id {
+            page = (page + 1).min(pages.len() - 1);
+        } else if press.data.custom_id == prev_button_id {
+            page = page.saturating_sub(1);
+        } else {
+            continue;
+        }
+        press
+            .create_response(
+                ctx.serenity_context(),
+                serenity::CreateInteractionResponse::UpdateMessage(
+                    pages[page].update(header).components(buttons(page)),
+                ),
+            )
+            .await?;
+    }
+    // The pages are dropped now, so take away the buttons
+    reply
+        .edit(
+            ctx,
+            pages[page].reply(header).components(vec![]),
+        )
+        .await?;
+    Ok(())
+}
+
+impl Page {
+    /// A new message showing this page
+    fn reply(&self, header: &str) -> poise::CreateReply {
+        let text = format!("{}{}", header, self.heading);
+        match &self.body {
+            PageBody::Table(table) => {
+                poise::CreateReply::default().content(format!("{}{}", text, table))
+            }
+            PageBody::Embeds(embeds) => {
+                let reply = poise::CreateReply::default().content(text);
+                embeds
+                    .iter()
+                    .fold(reply, |reply, embed| reply.embed(embed.clone()))
+            }
+        }
+    }
+
+    /// An update switching an existing message to this page
+    fn update(&self, header: &str) -> serenity::CreateInteractionResponseMessage {
+        let message = serenity::CreateInteractionResponseMessage::new();
+        let text = format!("{}{}", header, self.heading);
+        match &self.body {
+            PageBody::Table(table) => message.content(format!("{}{}", text, table)),
+            PageBody::Embeds(embeds) => message.content(text).embeds(embeds.clone()),
+        }
+    }
+}
+
+/// Split listings into pages in the given style
+fn render_listings(
+    style: DisplayStyle,
+    listings: Vec<Listing>,
+    reputations: &HashMap<u64, Reputation>,
+) -> Vec<Page> {
+    let bodies = match style {
+        DisplayStyle::Table => format_listings(listings, reputations)
+            .into_iter()
+            .map(PageBody::Table)
+            .collect::<Vec<_>>(),
+        DisplayStyle::Embed => listings
+            .chunks(EMBEDS_PER_PAGE)
+            .map(|chunk| {
+                PageBody::Embeds(
+                    chunk
+                        .iter()
+                        .map(|listing| listing_embed(listing, reputations))
+                        .collect(),
+                )
+            })
+            .collect(),
+    };
+    bodies
+        .into_iter()
+        .map(|body| Page {
+            heading: "".to_string(),
+            body,
+        })
+        .collect()
+}
+
+/// Listings trading one item, under that item's name
+type ListingGroup = (String, Vec<Listing>);
+
+/// Split groups of listings into pages, each page headed by the name of its group
+fn render_groups(
+    style: DisplayStyle,
+    groups: Vec<ListingGroup>,
+    reputations: &HashMap<u64, Reputation>,
+) -> Vec<Page> {
+    groups
+        .into_iter()
+        .flat_map(|(name, listings)| {
+            render_listings(style, listings, reputations)
+                .into_iter()
+                .map(move |page| Page {
+                    heading: format!("**{}**\n", name),
+                    ..page
+                })
+        })
+        .collect()
+}
+
+/// Format a listing as an embed
+fn listing_embed(listing: &Listing, reputations: &HashMap<u64, Reputation>) -> serenity::CreateEmbed {
+    let mut embed = serenity::CreateEmbed::new()
+        .title(format!(
+            "{} {} for {} {}",
+            listing.offer_quantity, listing.offer_item, listing.request_quantity, listing.request_item
+        ))
+        .field(
+            "Each",
+            format!("{} {}", format_price(listing.unit_price()), listing.request_item),
+            true,
+        )
+        .field(
+            "Location",
+            match listing.distance {
+                Some(distance) => format!(
+                    "N:{} E:{} ({:.0} away)",
+                    listing.location_north, listing.location_east, distance
+                ),
+                None => format!("N:{} E:{}", listing.location_north, listing.location_east),
+            },
+            true,
+        )
+        .field("Stock", listing.offer_count.to_string(), true)
+        .field("Renewed", format_renewed(listing.renewed_days_ago), true)
+        .footer(serenity::CreateEmbedFooter::new(format!("Listing ID {}", listing.id)));
+    if !listing.description.is_empty() {
+        embed = embed.description(&listing.description);
+    }
+    if listing.user != "cyypherus" {
+        embed = embed.field("Owner", format_user(listing, reputations), true);
+    }
+    embed
+}
+
+/// Format a listing's owner with their rating, e.g. "trader 4.5/5 (12)"
+fn format_user(listing: &Listing, reputations: &HashMap<u64, Reputation>) -> String {
+    if listing.user == "cyypherus" {
+        return "".to_string();
+    }
+    match listing.user_id.and_then(|user_id| reputations.get(&user_id)) {
+        Some(reputation) => format!(
+            "{} {:.1}/5 ({})",
+            listing.user, reputation.average, reputation.count
+        ),
+        None => listing.user.clone(),
+    }
+}
+
+/// Look up the ratings of the owners of some listings
+fn with_reputations(
+    db: &rusqlite::Connection,
+    listings: Vec<Listing>,
+) -> Result<(Vec<Listing>, HashMap<u64, Reputation>), Error> {
+    let reputations =
+        FeedbackStore::new(db).reputations(listings.iter().filter_map(|listing| listing.user_id))?;
+    Ok((listings, reputations))
+}
+
+/// Format a price with as many decimals as it needs, e.g. "25", "0.5" or "0.004"
+fn format_price(price: f64) -> String {
+    let decimals = if price >= 1.0 || price <= 0.0 {
+        2
+    } else {
+        (-price.log10()).floor() as usize + 2
+    };
+    let formatted = format!("{:.*}", decimals, price);
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Describe how long ago a listing was renewed, e.g. "3 days ago"
+fn format_renewed(days_ago: i64) -> String {
+    match days_ago {
+        0 => "today".to_string(),
+        1 => "1 day ago".to_string(),
+        days => format!("{} days ago", days),
+    }
+}
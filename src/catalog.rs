@@ -0,0 +1,651 @@
+use crate::Error;
+use csv::ReaderBuilder;
+use rusqlite::{params, Connection};
+use std::collections::{HashMap, HashSet};
+
+/// Which data file an item comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, poise::ChoiceParameter)]
+pub enum ItemKind {
+    #[name = "cargo"]
+    Cargo,
+    #[name = "item"]
+    Item,
+}
+
+impl ItemKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ItemKind::Cargo => "cargo",
+            ItemKind::Item => "item",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<ItemKind> {
+        match kind {
+            "cargo" => Some(ItemKind::Cargo),
+            "item" => Some(ItemKind::Item),
+            _ => None,
+        }
+    }
+}
+
+/// An item as described by a data file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemDefinition {
+    pub name: String,
+    pub tier: Option<i32>,
+    pub kind: ItemKind,
+    /// Other names the item is known by, including names it used to have
+    pub aliases: Vec<String>,
+}
+
+/// An item with the ID listings refer to it by
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatalogItem {
+    pub id: i64,
+    pub name: String,
+    pub tier: Option<i32>,
+    pub kind: ItemKind,
+    pub aliases: Vec<String>,
+}
+
+impl CatalogItem {
+    /// Name shown to users & stored on listings, e.g. "Rough Cloth (T1)"
+    pub fn display_name(&self) -> String {
+        display_name(&self.name, self.tier)
+    }
+}
+
+fn display_name(name: &str, tier: Option<i32>) -> String {
+    match tier {
+        Some(tier) => format!("{} (T{})", name, tier),
+        None => name.to_string(),
+    }
+}
+
+/// Parse a `|` separated data file with name, tier & optional `;` separated aliases columns.
+/// A tier of -1 means the item has none
+pub fn parse_item_file(data: &str, kind: ItemKind) -> Result<Vec<ItemDefinition>, Error> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(b'|')
+        .quoting(false)
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(data.as_bytes());
+    let mut items = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let name = record.get(0).unwrap_or("").trim().to_string();
+        if name.is_empty() {
+            continue;
+        }
+        let tier = record.get(1).unwrap_or("").trim();
+        let tier: i32 = tier
+            .parse()
+            .map_err(|_| format!("Invalid tier {:?} for {}", tier, name))?;
+        let aliases = record
+            .get(2)
+            .unwrap_or("")
+            .split(';')
+            .map(str::trim)
+            .filter(|alias| !alias.is_empty())
+            .map(str::to_string)
+            .collect();
+        items.push(ItemDefinition {
+            name,
+            tier: (tier != -1).then_some(tier),
+            kind,
+            aliases,
+        });
+    }
+    Ok(items)
+}
+
+/// Which catalog items to browse
+#[derive(Debug, Clone, Default)]
+pub struct ItemFilter {
+    pub kind: Option<ItemKind>,
+    pub min_tier: Option<i32>,
+    pub max_tier: Option<i32>,
+    /// Part of the item's display name or one of its aliases, ignoring case
+    pub keyword: Option<String>,
+}
+
+impl ItemFilter {
+    fn matches(&self, item: &CatalogItem) -> bool {
+        let in_tier_range = match item.tier {
+            Some(tier) => {
+                self.min_tier.is_none_or(|min_tier| tier >= min_tier)
+                    && self.max_tier.is_none_or(|max_tier| tier <= max_tier)
+            }
+            None => self.min_tier.is_none() && self.max_tier.is_none(),
+        };
+        let has_keyword = self.keyword.as_ref().is_none_or(|keyword| {
+            let keyword = keyword.trim().to_lowercase();
+            item.display_name().to_lowercase().contains(&keyword)
+                || item
+                    .aliases
+                    .iter()
+                    .any(|alias| alias.to_lowercase().contains(&keyword))
+        });
+        self.kind.is_none_or(|kind| item.kind == kind) && in_tier_range && has_keyword
+    }
+}
+
+/// Differences between two versions of the catalog
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CatalogChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Old & new display names of items that kept their ID
+    pub renamed: Vec<(String, String)>,
+}
+
+impl CatalogChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.renamed.is_empty()
+    }
+}
+
+/// Every tradeable item, looked up by ID, display name or alias
+#[derive(Debug, Clone, Default)]
+pub struct ItemCatalog {
+    items: Vec<CatalogItem>,
+    /// Lowercased display names & aliases to positions in `items`
+    names: HashMap<String, usize>,
+}
+
+impl ItemCatalog {
+    /// Give each item an ID, reusing the ID saved for it last time. An item whose name changed
+    /// keeps its ID if one of its aliases is the old name, so everything stored under the ID
+    /// shows the new name
+    pub fn sync(
+        db: &Connection,
+        mut definitions: Vec<ItemDefinition>,
+    ) -> Result<ItemCatalog, Error> {
+        // The data files list a few items twice
+        let mut seen = HashSet::new();
+        definitions.retain(|definition| {
+            seen.insert((
+                definition.kind,
+                display_name(&definition.name, definition.tier),
+            ))
+        });
+        let tx = db.unchecked_transaction()?;
+        let mut saved = HashMap::<(ItemKind, String), i64>::new();
+        {
+            let mut stmt = tx.prepare("SELECT id, kind, display_name FROM items")?;
+            let rows = stmt.query_map((), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get(2)?))
+            })?;
+            for row in rows {
+                let (id, kind, display_name) = row?;
+                if let Some(kind) = ItemKind::parse(&kind) {
+                    saved.insert((kind, display_name), id);
+                }
+            }
+        }
+
+        let current_names = definitions
+            .iter()
+            .map(|definition| display_name(&definition.name, definition.tier))
+            .collect::<Vec<_>>();
+        let mut items = Vec::new();
+        for (definition, current) in definitions.into_iter().zip(current_names.iter()) {
+            let current = current.clone();
+            // An alias only marks a rename if no item still goes by that name
+            let renamed_from = definition.aliases.iter().find(|alias| {
+                saved.contains_key(&(definition.kind, alias.to_string()))
+                    && !current_names.contains(alias)
+            });
+            let id = match (saved.get(&(definition.kind, current.clone())), renamed_from) {
+                (Some(&id), _) => id,
+                (None, Some(old)) => saved[&(definition.kind, old.clone())],
+                (None, None) => {
+                    tx.execute(
+                        "INSERT INTO items (kind, name, tier, display_name) VALUES (?, ?, ?, ?)",
+                        params![
+                            definition.kind.as_str(),
+                            definition.name,
+                            definition.tier,
+                            current
+                        ],
+                    )?;
+                    tx.last_insert_rowid()
+                }
+            };
+            tx.execute(
+                "UPDATE items SET name = ?, tier = ?, display_name = ? WHERE id = ?",
+                params![definition.name, definition.tier, current, id],
+            )?;
+            items.push(CatalogItem {
+                id,
+                name: definition.name,
+                tier: definition.tier,
+                kind: definition.kind,
+                aliases: definition.aliases,
+            });
+        }
+
+        // Link rows stored before items had IDs
+        for (table, name_column, id_column) in [
+            ("listings", "sale_item", "sale_item_id"),
+            ("listings", "buy_item", "buy_item_id"),
+            ("watches", "item", "item_id"),
+            ("watches", "counter_item", "counter_item_id"),
+            ("listing_history", "sale_item", "sale_item_id"),
+            ("listing_history", "buy_item", "buy_item_id"),
+            ("trades", "sale_item", "sale_item_id"),
+            ("trades", "buy_item", "buy_item_id"),
+        ] {
+            tx.execute(
+                &format!(
+                    "UPDATE {table} SET {id_column} = (SELECT id FROM items WHERE display_name = {name_column})
+                    WHERE {id_column} IS NULL",
+                ),
+                (),
+            )?;
+        }
+        tx.commit()?;
+        Ok(ItemCatalog::new(items))
+    }
+
+    fn new(items: Vec<CatalogItem>) -> ItemCatalog {
+        let mut names = HashMap::new();
+        // Real names win over aliases that happen to match them
+        for (position, item) in items.iter().enumerate() {
+            for alias in &item.aliases {
+                names.insert(alias.to_lowercase(), position);
+            }
+        }
+        for (position, item) in items.iter().enumerate() {
+            names.insert(item.display_name().to_lowercase(), position);
+        }
+        ItemCatalog { items, names }
+    }
+
+    /// Find an item by display name or alias, ignoring case
+    pub fn find(&self, name: &str) -> Option<&CatalogItem> {
+        self.names
+            .get(&name.trim().to_lowercase())
+            .map(|&position| &self.items[position])
+    }
+
+    pub fn get(&self, id: i64) -> Option<&CatalogItem> {
+        self.items.iter().find(|item| item.id == id)
+    }
+
+    pub fn items(&self) -> &[CatalogItem] {
+        &self.items
+    }
+
+    /// What changed from this catalog to `newer`, with display names sorted
+    pub fn changes(&self, newer: &ItemCatalog) -> CatalogChanges {
+        let mut changes = CatalogChanges::default();
+        for item in &newer.items {
+            match self.get(item.id) {
+                None => changes.added.push(item.display_name()),
+                Some(old) if old.display_name() != item.display_name() => changes
+                    .renamed
+                    .push((old.display_name(), item.display_name())),
+                Some(_) => {}
+            }
+        }
+        changes.removed = self
+            .items
+            .iter()
+            .filter(|item| newer.get(item.id).is_none())
+            .map(|item| item.display_name())
+            .collect();
+        changes.added.sort();
+        changes.removed.sort();
+        changes.renamed.sort();
+        changes
+    }
+
+    /// Items that listings offer or request but that aren't in the catalog, by their last known
+    /// name, with how many listings trade each
+    pub fn orphaned_items(&self, db: &Connection) -> Result<Vec<(String, i32)>, Error> {
+        let mut stmt = db.prepare(
+            "SELECT item_id, name, COUNT(DISTINCT listing_id) FROM (
+                SELECT id AS listing_id, sale_item_id AS item_id,
+                    COALESCE((SELECT display_name FROM items WHERE items.id = sale_item_id), sale_item) AS name
+                FROM listings
+                UNION ALL
+                SELECT id AS listing_id, buy_item_id AS item_id,
+                    COALESCE((SELECT display_name FROM items WHERE items.id = buy_item_id), buy_item) AS name
+                FROM listings
+            )
+            GROUP BY item_id, name
+            ORDER BY name",
+        )?;
+        let items = stmt
+            .query_map((), |row| {
+                Ok((row.get::<_, Option<i64>>(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(Option<i64>, String, i32)>>>()?;
+        Ok(items
+            .into_iter()
+            .filter(|(id, _, _)| id.is_none_or(|id| self.get(id).is_none()))
+            .map(|(_, item, count)| (item, count))
+            .collect())
+    }
+
+    /// Items matching `filter`, sorted by display name
+    pub fn browse(&self, filter: &ItemFilter) -> Vec<&CatalogItem> {
+        let mut items = self
+            .items
+            .iter()
+            .filter(|item| filter.matches(item))
+            .collect::<Vec<_>>();
+        items.sort_by_cached_key(|item| item.display_name().to_lowercase());
+        items
+    }
+
+    /// Every tier of the item called `name`, ignoring case, lowest tier first. Items without a
+    /// tier are only included when there's no tier range
+    pub fn tiers(
+        &self,
+        name: &str,
+        min_tier: Option<i32>,
+        max_tier: Option<i32>,
+    ) -> Vec<&CatalogItem> {
+        let name = name.trim();
+        let filter = ItemFilter {
+            min_tier,
+            max_tier,
+            ..Default::default()
+        };
+        let mut tiers = self
+            .items
+            .iter()
+            .filter(|item| item.name.eq_ignore_ascii_case(name) && filter.matches(item))
+            .collect::<Vec<_>>();
+        tiers.sort_by_key(|item| item.tier);
+        tiers.dedup_by_key(|item| item.tier);
+        tiers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listings::{Listing, ListingStore};
+    use crate::test_support::{insert, listing, test_db};
+    use crate::watches::{Watch, WatchStore};
+
+    fn definition(name: &str, tier: i32, aliases: &[&str]) -> ItemDefinition {
+        ItemDefinition {
+            name: name.to_string(),
+            tier: (tier != -1).then_some(tier),
+            kind: ItemKind::Item,
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+        }
+    }
+
+    fn item_ids(db: &Connection, listing_id: i32) -> (Option<i64>, Option<i64>) {
+        db.query_row(
+            "SELECT sale_item_id, buy_item_id FROM listings WHERE id = ?",
+            params![listing_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_tiers_and_aliases() {
+        let data =
+            "\u{feff}name | tier | aliases\n Rough Cloth | 1 | Cloth; Old Cloth \n Hex Coin | -1\n";
+        assert_eq!(
+            parse_item_file(data, ItemKind::Cargo).unwrap(),
+            vec![
+                ItemDefinition {
+                    name: "Rough Cloth".to_string(),
+                    tier: Some(1),
+                    kind: ItemKind::Cargo,
+                    aliases: vec!["Cloth".to_string(), "Old Cloth".to_string()],
+                },
+                ItemDefinition {
+                    name: "Hex Coin".to_string(),
+                    tier: None,
+                    kind: ItemKind::Cargo,
+                    aliases: vec![],
+                },
+            ]
+        );
+        assert!(parse_item_file("name | tier\n Rough Cloth | one\n", ItemKind::Item).is_err());
+    }
+
+    #[test]
+    fn ids_are_stable_across_syncs() {
+        let db = test_db();
+        let first = ItemCatalog::sync(
+            &db,
+            vec![
+                definition("Rough Cloth", 1, &[]),
+                definition("Hex Coin", -1, &[]),
+            ],
+        )
+        .unwrap();
+        let second = ItemCatalog::sync(
+            &db,
+            vec![
+                definition("Fine Cloth", 3, &[]),
+                definition("Hex Coin", -1, &["coins"]),
+                definition("Rough Cloth", 1, &[]),
+            ],
+        )
+        .unwrap();
+        for item in first.items() {
+            assert_eq!(second.find(&item.display_name()).unwrap().id, item.id);
+        }
+        assert_eq!(second.find("COINS").unwrap().display_name(), "Hex Coin");
+        assert_eq!(second.find("rough cloth (t1)").unwrap().name, "Rough Cloth");
+        assert_eq!(second.find("Rough Cloth"), None);
+    }
+
+    #[test]
+    fn finds_every_tier_in_range() {
+        let db = test_db();
+        let catalog = ItemCatalog::sync(
+            &db,
+            vec![
+                definition("Simple Wood Trunk", 4, &[]),
+                definition("Simple Wood Trunk", 2, &[]),
+                definition("Simple Wood Trunk", 3, &[]),
+                definition("Simple Wood Trunk Pile", 2, &[]),
+            ],
+        )
+        .unwrap();
+        let names = |min_tier, max_tier| {
+            catalog
+                .tiers("simple wood trunk", min_tier, max_tier)
+                .iter()
+                .map(|item| item.display_name())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(None, None),
+            vec![
+                "Simple Wood Trunk (T2)",
+                "Simple Wood Trunk (T3)",
+                "Simple Wood Trunk (T4)"
+            ]
+        );
+        assert_eq!(
+            names(Some(3), None),
+            vec!["Simple Wood Trunk (T3)", "Simple Wood Trunk (T4)"]
+        );
+        assert_eq!(names(Some(2), Some(2)), vec!["Simple Wood Trunk (T2)"]);
+        assert_eq!(names(Some(5), None), Vec::<String>::new());
+    }
+
+    #[test]
+    fn renamed_items_keep_their_id_and_listings() {
+        let db = test_db();
+        let catalog = ItemCatalog::sync(
+            &db,
+            vec![
+                definition("Rough Cloth", 1, &[]),
+                definition("Hex Coin", -1, &[]),
+            ],
+        )
+        .unwrap();
+        let cloth_id = catalog.find("Rough Cloth (T1)").unwrap().id;
+        let listing = insert(&db, listing("Rough Cloth (T1)", "Hex Coin"));
+        assert_eq!(
+            item_ids(&db, listing.id),
+            (Some(cloth_id), Some(catalog.find("Hex Coin").unwrap().id))
+        );
+
+        let watches = WatchStore::new(&db);
+        let watch = watches
+            .insert(Watch {
+                id: 0,
+                user_id: 2,
+                item_id: cloth_id,
+                location_north: 0,
+                location_east: 0,
+                distance: 10,
+                counter_item_id: None,
+                max_price: None,
+                guild_id: None,
+            })
+            .unwrap();
+
+        let catalog = ItemCatalog::sync(
+            &db,
+            vec![
+                definition("Coarse Cloth", 1, &["Rough Cloth (T1)"]),
+                definition("Hex Coin", -1, &[]),
+            ],
+        )
+        .unwrap();
+        assert_eq!(catalog.find("Coarse Cloth (T1)").unwrap().id, cloth_id);
+        assert_eq!(catalog.find("Rough Cloth (T1)").unwrap().id, cloth_id);
+        let listing = ListingStore::new(&db).get(listing.id).unwrap().unwrap();
+        assert_eq!(listing.offer_item, "Coarse Cloth (T1)");
+        assert_eq!(item_ids(&db, listing.id).0, Some(cloth_id));
+        // Watches set under the old name still hear about the item
+        assert_eq!(watches.matching(&listing).unwrap(), vec![watch]);
+    }
+
+    #[test]
+    fn links_listings_posted_before_ids() {
+        let db = test_db();
+        let listing = insert(
+            &db,
+            Listing {
+                offer_item_id: None,
+                request_item_id: None,
+                ..listing("Rough Cloth (T1)", "Hex Coin")
+            },
+        );
+        assert_eq!(item_ids(&db, listing.id), (None, None));
+        let catalog = ItemCatalog::sync(
+            &db,
+            vec![
+                definition("Rough Cloth", 1, &[]),
+                definition("Hex Coin", -1, &[]),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            item_ids(&db, listing.id),
+            (
+                Some(catalog.find("Rough Cloth (T1)").unwrap().id),
+                Some(catalog.find("Hex Coin").unwrap().id)
+            )
+        );
+    }
+
+    #[test]
+    fn browses_by_kind_tier_and_keyword() {
+        let db = test_db();
+        let catalog = ItemCatalog::sync(
+            &db,
+            vec![
+                definition("Rough Cloth", 1, &["Linen"]),
+                definition("Fine Cloth", 3, &[]),
+                definition("Hex Coin", -1, &[]),
+                ItemDefinition {
+                    kind: ItemKind::Cargo,
+                    ..definition("Cloth Bundle", 2, &[])
+                },
+            ],
+        )
+        .unwrap();
+        let names = |filter: ItemFilter| {
+            catalog
+                .browse(&filter)
+                .iter()
+                .map(|item| item.display_name())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(ItemFilter {
+                keyword: Some("cloth".to_string()),
+                ..Default::default()
+            }),
+            vec!["Cloth Bundle (T2)", "Fine Cloth (T3)", "Rough Cloth (T1)"]
+        );
+        assert_eq!(
+            names(ItemFilter {
+                kind: Some(ItemKind::Item),
+                min_tier: Some(2),
+                ..Default::default()
+            }),
+            vec!["Fine Cloth (T3)"]
+        );
+        assert_eq!(
+            names(ItemFilter {
+                keyword: Some("linen".to_string()),
+                ..Default::default()
+            }),
+            vec!["Rough Cloth (T1)"]
+        );
+        assert_eq!(names(ItemFilter::default()).len(), 4);
+    }
+
+    #[test]
+    fn reports_changes_and_orphaned_listings() {
+        let db = test_db();
+        let old = ItemCatalog::sync(
+            &db,
+            vec![
+                definition("Rough Cloth", 1, &[]),
+                definition("Hex Coin", -1, &[]),
+                definition("Fine Geode", 4, &[]),
+            ],
+        )
+        .unwrap();
+        insert(&db, listing("Fine Geode (T4)", "Hex Coin"));
+        insert(&db, listing("Hex Coin", "Fine Geode (T4)"));
+        let new = ItemCatalog::sync(
+            &db,
+            vec![
+                definition("Coarse Cloth", 1, &["Rough Cloth (T1)"]),
+                definition("Hex Coin", -1, &[]),
+                definition("Fine Plank", 2, &[]),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            old.changes(&new),
+            CatalogChanges {
+                added: vec!["Fine Plank (T2)".to_string()],
+                removed: vec!["Fine Geode (T4)".to_string()],
+                renamed: vec![(
+                    "Rough Cloth (T1)".to_string(),
+                    "Coarse Cloth (T1)".to_string()
+                )],
+            }
+        );
+        assert!(new.changes(&new).is_empty());
+        assert_eq!(
+            new.orphaned_items(&db).unwrap(),
+            vec![("Fine Geode (T4)".to_string(), 2)]
+        );
+        assert!(old.orphaned_items(&db).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::migrations;
+use crate::Error;
+use rusqlite::Connection;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Shared handle to the bot's SQLite database.
+/// Every query runs on tokio's blocking thread pool so slow disk access never stalls the event loop.
+#[derive(Clone)]
+pub struct Db {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Db {
+    /// Open the database at `path` in WAL mode & bring its schema up to date
+    pub fn open(path: impl AsRef<Path>) -> Result<Db, Error> {
+        let connection = Connection::open(path)?;
+        connection
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        Db::new(connection)
+    }
+
+    /// Open a private in-memory database, used by tests
+    pub fn open_in_memory() -> Result<Db, Error> {
+        Db::new(Connection::open_in_memory()?)
+    }
+
+    fn new(mut connection: Connection) -> Result<Db, Error> {
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        migrations::migrate(&mut connection)?;
+        Ok(Db {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run `f` against the connection on a blocking thread
+    pub async fn call<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| "Database connection poisoned")?;
+            f(&mut connection)
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn in_memory_database_is_migrated() {
+        let db = Db::open_in_memory().unwrap();
+        let version = db
+            .call(|db| Ok(migrations::schema_version(db)?))
+            .await
+            .unwrap();
+        assert_eq!(version, migrations::SCHEMA_VERSION);
+    }
+
+    #[tokio::test]
+    async fn file_database_uses_wal() {
+        let path = std::env::temp_dir().join(format!("bort-wal-{}.db3", std::process::id()));
+        let db = Db::open(&path).unwrap();
+        let mode: String = db
+            .call(|db| Ok(db.pragma_query_value(None, "journal_mode", |row| row.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(mode, "wal");
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
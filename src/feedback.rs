@@ -0,0 +1,188 @@
+use crate::listings::MAX_DESCRIPTION_LENGTH;
+use crate::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+
+/// Lowest & highest score a rating can give
+pub const SCORE_RANGE: std::ops::RangeInclusive<i32> = 1..=5;
+/// Longest comment a rating can have
+pub const MAX_COMMENT_LENGTH: usize = MAX_DESCRIPTION_LENGTH;
+
+/// A user's average rating from other traders
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reputation {
+    pub average: f64,
+    pub count: i32,
+}
+
+/// Result of rating a user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateOutcome {
+    Rated,
+    /// No listing with that ID has ever been posted
+    ListingNotFound,
+    /// The listing belongs to someone other than the rated user
+    NotOwner,
+    /// Users can't rate their own listings
+    OwnListing,
+    /// The rater has already rated this listing
+    AlreadyRated,
+    /// The comment is longer than `MAX_COMMENT_LENGTH`
+    CommentTooLong,
+}
+
+/// All reads & writes of the feedback table
+pub struct FeedbackStore<'a> {
+    db: &'a Connection,
+}
+
+impl<'a> FeedbackStore<'a> {
+    pub fn new(db: &'a Connection) -> Self {
+        FeedbackStore { db }
+    }
+
+    /// Rate the owner of a listing, once per rater per listing
+    pub fn rate(
+        &self,
+        rater_id: u64,
+        user_id: u64,
+        listing_id: i32,
+        score: i32,
+        comment: &str,
+    ) -> Result<RateOutcome, Error> {
+        if comment.len() > MAX_COMMENT_LENGTH {
+            return Ok(RateOutcome::CommentTooLong);
+        }
+        // The listing may be gone by now, so fall back to its history
+        let owner: Option<u64> = self
+            .db
+            .query_row(
+                "SELECT user_id FROM listings WHERE id = ?1 AND user_id IS NOT NULL
+                UNION ALL
+                SELECT user_id FROM listing_history WHERE listing_id = ?1 AND user_id IS NOT NULL
+                LIMIT 1",
+                params![listing_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let outcome = match owner {
+            None => RateOutcome::ListingNotFound,
+            Some(owner) if owner == rater_id => RateOutcome::OwnListing,
+            Some(owner) if owner != user_id => RateOutcome::NotOwner,
+            Some(_) => {
+                let inserted = self.db.execute(
+                    "INSERT OR IGNORE INTO feedback (rater_id, user_id, listing_id, score, comment)
+                    VALUES (?, ?, ?, ?, ?)",
+                    params![rater_id, user_id, listing_id, score, comment],
+                )?;
+                if inserted == 0 {
+                    RateOutcome::AlreadyRated
+                } else {
+                    RateOutcome::Rated
+                }
+            }
+        };
+        Ok(outcome)
+    }
+
+    /// Get a user's average rating, `None` if nobody has rated them
+    pub fn reputation(&self, user_id: u64) -> Result<Option<Reputation>, Error> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT AVG(score), COUNT(*) FROM feedback WHERE user_id = ? HAVING COUNT(*) > 0",
+        )?;
+        Ok(stmt
+            .query_row(params![user_id], |row| {
+                Ok(Reputation {
+                    average: row.get(0)?,
+                    count: row.get(1)?,
+                })
+            })
+            .optional()?)
+    }
+
+    /// Get the ratings of every rated user among `user_ids`
+    pub fn reputations(
+        &self,
+        user_ids: impl IntoIterator<Item = u64>,
+    ) -> Result<HashMap<u64, Reputation>, Error> {
+        let mut reputations = HashMap::new();
+        for user_id in user_ids {
+            if reputations.contains_key(&user_id) {
+                continue;
+            }
+            if let Some(reputation) = self.reputation(user_id)? {
+                reputations.insert(user_id, reputation);
+            }
+        }
+        Ok(reputations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listings::ListingStore;
+    use crate::test_support::{insert, listing, test_db};
+
+    #[test]
+    fn one_rating_per_rater_per_listing() {
+        let db = test_db();
+        let store = FeedbackStore::new(&db);
+        let listing = insert(&db, listing("Rough Cloth (T1)", "Hex Coin"));
+
+        assert_eq!(store.reputation(1).unwrap(), None);
+        assert_eq!(
+            store.rate(2, 1, listing.id, 5, "quick trade").unwrap(),
+            RateOutcome::Rated
+        );
+        assert_eq!(
+            store.rate(2, 1, listing.id, 1, "changed my mind").unwrap(),
+            RateOutcome::AlreadyRated
+        );
+        assert_eq!(
+            store.rate(3, 1, listing.id, 2, "").unwrap(),
+            RateOutcome::Rated
+        );
+        assert_eq!(
+            store.reputation(1).unwrap(),
+            Some(Reputation {
+                average: 3.5,
+                count: 2
+            })
+        );
+        assert_eq!(store.reputations([1, 2, 1]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_own_missing_and_mismatched_listings() {
+        let db = test_db();
+        let store = FeedbackStore::new(&db);
+        let listing = insert(&db, listing("Rough Cloth (T1)", "Hex Coin"));
+
+        assert_eq!(
+            store.rate(1, 1, listing.id, 5, "").unwrap(),
+            RateOutcome::OwnListing
+        );
+        assert_eq!(
+            store.rate(2, 3, listing.id, 5, "").unwrap(),
+            RateOutcome::NotOwner
+        );
+        assert_eq!(
+            store.rate(2, 1, listing.id + 1, 5, "").unwrap(),
+            RateOutcome::ListingNotFound
+        );
+        assert_eq!(
+            store
+                .rate(2, 1, listing.id, 5, &"a".repeat(MAX_COMMENT_LENGTH + 1))
+                .unwrap(),
+            RateOutcome::CommentTooLong
+        );
+
+        // Listings can still be rated once they're gone
+        ListingStore::new(&db).delete(listing.id, 1).unwrap();
+        assert_eq!(
+            store.rate(2, 1, listing.id, 4, "").unwrap(),
+            RateOutcome::Rated
+        );
+    }
+}
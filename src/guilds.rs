@@ -0,0 +1,285 @@
+use crate::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// Which listings can be seen from somewhere
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Market {
+    /// Listings from DMs & guilds without a private market
+    #[default]
+    Public,
+    /// A guild's own listings, plus the public ones if it opted in
+    Private { guild_id: u64, include_public: bool },
+}
+
+/// Condition on the listings table keeping the listings visible in a market, using the
+/// `:market_guild` & `:include_public` parameters from `Market::params`
+pub(crate) const MARKET_CONDITION: &str = "(
+    (:market_guild IS NOT NULL AND guild_id = :market_guild)
+    OR ((:market_guild IS NULL OR :include_public) AND (
+        guild_id IS NULL
+        OR guild_id NOT IN (SELECT guild_id FROM guild_settings WHERE private_market)
+    ))
+)";
+
+impl Market {
+    /// Values of the `:market_guild` & `:include_public` parameters of `MARKET_CONDITION`
+    pub(crate) fn params(&self) -> (Option<u64>, bool) {
+        match *self {
+            Market::Public => (None, false),
+            Market::Private {
+                guild_id,
+                include_public,
+            } => (Some(guild_id), include_public),
+        }
+    }
+}
+
+/// A guild's market settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GuildSettings {
+    pub guild_id: u64,
+    /// Listings posted in the guild are only shown in the guild
+    pub private_market: bool,
+    /// A private market's members also see the public market
+    pub include_public: bool,
+}
+
+impl GuildSettings {
+    /// The market the guild's members trade in
+    pub fn market(&self) -> Market {
+        if self.private_market {
+            Market::Private {
+                guild_id: self.guild_id,
+                include_public: self.include_public,
+            }
+        } else {
+            Market::Public
+        }
+    }
+}
+
+/// All reads & writes of the guild_settings table
+pub struct GuildStore<'a> {
+    db: &'a Connection,
+}
+
+impl<'a> GuildStore<'a> {
+    pub fn new(db: &'a Connection) -> Self {
+        GuildStore { db }
+    }
+
+    /// Get a guild's settings, the public market's if it has none saved
+    pub fn settings(&self, guild_id: u64) -> Result<GuildSettings, Error> {
+        let settings = self
+            .db
+            .query_row(
+                "SELECT private_market, include_public FROM guild_settings WHERE guild_id = ?",
+                params![guild_id],
+                |row| {
+                    Ok(GuildSettings {
+                        guild_id,
+                        private_market: row.get(0)?,
+                        include_public: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(settings.unwrap_or(GuildSettings {
+            guild_id,
+            private_market: false,
+            include_public: false,
+        }))
+    }
+
+    /// Save a guild's settings
+    pub fn set(&self, settings: &GuildSettings) -> Result<(), Error> {
+        self.db.execute(
+            "INSERT INTO guild_settings (guild_id, private_market, include_public)
+            VALUES (?, ?, ?)
+            ON CONFLICT (guild_id) DO UPDATE SET
+                private_market = excluded.private_market,
+                include_public = excluded.include_public",
+            params![
+                settings.guild_id,
+                settings.private_market,
+                settings.include_public
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The market a listing posted in a guild belongs to. Listings in private markets are only
+    /// matched within their guild, so nobody outside it hears about them
+    pub fn listing_market(&self, guild_id: Option<u64>) -> Result<Market, Error> {
+        let Some(guild_id) = guild_id else {
+            return Ok(Market::Public);
+        };
+        Ok(match self.settings(guild_id)?.market() {
+            Market::Private { guild_id, .. } => Market::Private {
+                guild_id,
+                include_public: false,
+            },
+            Market::Public => Market::Public,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listings::{ListingSearch, ListingStore};
+    use crate::matching::find_matches;
+    use crate::test_support::{post, test_db, ListingOverrides};
+
+    fn visible_ids(db: &Connection, market: Market) -> Vec<i32> {
+        ListingStore::new(db)
+            .search(&ListingSearch {
+                distance: 10,
+                market,
+                ..Default::default()
+            })
+            .unwrap()
+            .iter()
+            .map(|listing| listing.id)
+            .collect()
+    }
+
+    #[test]
+    fn private_markets_only_show_their_own_listings() {
+        let db = test_db();
+        let store = GuildStore::new(&db);
+        store
+            .set(&GuildSettings {
+                guild_id: 10,
+                private_market: true,
+                include_public: false,
+            })
+            .unwrap();
+        let from_dm = post(
+            &db,
+            (1, "Wood"),
+            (1, "Stone"),
+            ListingOverrides::owned_by(1),
+        )
+        .id;
+        let from_public_guild = post(
+            &db,
+            (1, "Wood"),
+            (1, "Stone"),
+            ListingOverrides {
+                guild_id: Some(20),
+                ..ListingOverrides::owned_by(1)
+            },
+        )
+        .id;
+        let from_private_guild = post(
+            &db,
+            (1, "Wood"),
+            (1, "Stone"),
+            ListingOverrides {
+                guild_id: Some(10),
+                ..ListingOverrides::owned_by(1)
+            },
+        )
+        .id;
+
+        assert_eq!(
+            visible_ids(&db, Market::Public),
+            vec![from_dm, from_public_guild]
+        );
+        let private = store.settings(10).unwrap().market();
+        assert_eq!(visible_ids(&db, private), vec![from_private_guild]);
+        assert_eq!(
+            ListingStore::new(&db)
+                .get_in(from_private_guild, Market::Public)
+                .unwrap(),
+            None
+        );
+
+        store
+            .set(&GuildSettings {
+                guild_id: 10,
+                private_market: true,
+                include_public: true,
+            })
+            .unwrap();
+        let private = store.settings(10).unwrap().market();
+        assert_eq!(
+            visible_ids(&db, private),
+            vec![from_dm, from_public_guild, from_private_guild]
+        );
+        // Opting into the public market doesn't publish the guild's listings
+        assert_eq!(
+            visible_ids(&db, Market::Public),
+            vec![from_dm, from_public_guild]
+        );
+    }
+
+    #[test]
+    fn private_listings_only_match_within_their_guild() {
+        let db = test_db();
+        GuildStore::new(&db)
+            .set(&GuildSettings {
+                guild_id: 10,
+                private_market: true,
+                include_public: true,
+            })
+            .unwrap();
+        let public = post(
+            &db,
+            (1, "Wood"),
+            (1, "Stone"),
+            ListingOverrides::owned_by(1),
+        )
+        .id;
+        let same_guild = post(
+            &db,
+            (1, "Wood"),
+            (1, "Stone"),
+            ListingOverrides {
+                guild_id: Some(10),
+                ..ListingOverrides::owned_by(2)
+            },
+        )
+        .id;
+        let listing = ListingStore::new(&db)
+            .get(
+                post(
+                    &db,
+                    (1, "Stone"),
+                    (1, "Wood"),
+                    ListingOverrides {
+                        guild_id: Some(10),
+                        ..ListingOverrides::owned_by(3)
+                    },
+                )
+                .id,
+            )
+            .unwrap()
+            .unwrap();
+
+        let matches = find_matches(&db, &listing, 10).unwrap();
+        assert_eq!(
+            matches.iter().map(|listing| listing.id).collect::<Vec<_>>(),
+            vec![same_guild]
+        );
+
+        let listing = ListingStore::new(&db)
+            .get(
+                post(
+                    &db,
+                    (1, "Stone"),
+                    (1, "Wood"),
+                    ListingOverrides::owned_by(3),
+                )
+                .id,
+            )
+            .unwrap()
+            .unwrap();
+        let matches = find_matches(&db, &listing, 10).unwrap();
+        assert_eq!(
+            matches.iter().map(|listing| listing.id).collect::<Vec<_>>(),
+            vec![public]
+        );
+    }
+}
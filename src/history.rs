@@ -0,0 +1,381 @@
+use crate::guilds::{Market, MARKET_CONDITION};
+use crate::Error;
+use rusqlite::{named_params, Connection, Params};
+
+/// What happened to a listing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListingEvent {
+    Created,
+    Removed,
+    Expired,
+    /// Removed after its last stock was traded
+    Completed,
+    /// Quantities changed, giving the listing a new price
+    Edited,
+}
+
+impl ListingEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ListingEvent::Created => "created",
+            ListingEvent::Removed => "removed",
+            ListingEvent::Expired => "expired",
+            ListingEvent::Completed => "completed",
+            ListingEvent::Edited => "edited",
+        }
+    }
+}
+
+/// Copy the listings matching `condition` into the history as `event`
+pub(crate) fn record<P: Params>(
+    db: &Connection,
+    event: ListingEvent,
+    condition: &str,
+    params: P,
+) -> rusqlite::Result<usize> {
+    db.execute(
+        &format!(
+            "INSERT INTO listing_history (listing_id, event, sale_quantity, sale_item, sale_item_id, buy_quantity, buy_item, buy_item_id, user_id, guild_id)
+            SELECT id, '{}', sale_quantity, sale_item, sale_item_id, buy_quantity, buy_item, buy_item_id, user_id, guild_id
+            FROM listings
+            WHERE {}",
+            event.as_str(),
+            condition
+        ),
+        params,
+    )
+}
+
+/// Summary of the exchange rates listings have offered for an item
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriceStats {
+    pub count: usize,
+    pub median: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl PriceStats {
+    /// Summarize a set of prices, `None` if there are none
+    pub fn from_prices(mut prices: Vec<f64>) -> Option<PriceStats> {
+        if prices.is_empty() {
+            return None;
+        }
+        prices.sort_by(|a, b| a.total_cmp(b));
+        let middle = prices.len() / 2;
+        let median = if prices.len().is_multiple_of(2) {
+            (prices[middle - 1] + prices[middle]) / 2.0
+        } else {
+            prices[middle]
+        };
+        Some(PriceStats {
+            count: prices.len(),
+            median,
+            min: prices[0],
+            max: prices[prices.len() - 1],
+        })
+    }
+}
+
+/// Reads of the listing history
+pub struct HistoryStore<'a> {
+    db: &'a Connection,
+}
+
+impl<'a> HistoryStore<'a> {
+    pub fn new(db: &'a Connection) -> Self {
+        HistoryStore { db }
+    }
+
+    /// Prices of an item in a counter item, by ID, from listings created in `market` in the last
+    /// `days` days, counting both listings selling the item and listings buying it
+    pub fn prices(
+        &self,
+        item_id: i64,
+        counter_item_id: i64,
+        days: i32,
+        market: Market,
+    ) -> Result<Vec<f64>, Error> {
+        let (market_guild, include_public) = market.params();
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT CASE WHEN sale_item_id = :item
+                THEN CAST(buy_quantity AS REAL) / sale_quantity
+                ELSE CAST(sale_quantity AS REAL) / buy_quantity
+            END
+            FROM listing_history
+            WHERE event IN ('created', 'edited')
+            AND ((sale_item_id = :item AND buy_item_id = :counter_item) OR (buy_item_id = :item AND sale_item_id = :counter_item))
+            AND timestamp >= datetime('now', :age)
+            AND {}",
+            MARKET_CONDITION
+        ))?;
+        let prices = stmt
+            .query_map(
+                named_params! {
+                    ":item": item_id,
+                    ":counter_item": counter_item_id,
+                    ":age": format!("-{} days", days),
+                    ":market_guild": market_guild,
+                    ":include_public": include_public,
+                },
+                |row| row.get(0),
+            )?
+            .collect::<rusqlite::Result<Vec<f64>>>()?;
+        Ok(prices)
+    }
+
+    /// IDs of the items that an item has been traded for in `market` in the last `days` days,
+    /// with how many listings traded each, most listed first. Counter items the catalog never
+    /// had are left out
+    pub fn counter_items(
+        &self,
+        item_id: i64,
+        days: i32,
+        market: Market,
+    ) -> Result<Vec<(i64, i32)>, Error> {
+        let (market_guild, include_public) = market.params();
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT CASE WHEN sale_item_id = :item THEN buy_item_id ELSE sale_item_id END AS counter_item, COUNT(*)
+            FROM listing_history
+            WHERE event IN ('created', 'edited')
+            AND (sale_item_id = :item OR buy_item_id = :item)
+            AND counter_item IS NOT NULL
+            AND timestamp >= datetime('now', :age)
+            AND {}
+            GROUP BY counter_item
+            ORDER BY COUNT(*) DESC, counter_item",
+            MARKET_CONDITION
+        ))?;
+        let counter_items = stmt
+            .query_map(
+                named_params! {
+                    ":item": item_id,
+                    ":age": format!("-{} days", days),
+                    ":market_guild": market_guild,
+                    ":include_public": include_public,
+                },
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+            .collect::<rusqlite::Result<Vec<(i64, i32)>>>()?;
+        Ok(counter_items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guilds::{GuildSettings, GuildStore};
+    use crate::listings::{Listing, ListingStore};
+    use crate::test_support::{insert, item_id, listing, post, test_db, ListingOverrides};
+    use rusqlite::params;
+
+    fn events(db: &Connection, listing_id: i32) -> Vec<String> {
+        let mut stmt = db
+            .prepare("SELECT event FROM listing_history WHERE listing_id = ? ORDER BY id")
+            .unwrap();
+        let events = stmt
+            .query_map(params![listing_id], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .unwrap();
+        events
+    }
+
+    #[test]
+    fn stats_of_prices() {
+        assert_eq!(PriceStats::from_prices(vec![]), None);
+        assert_eq!(
+            PriceStats::from_prices(vec![3.0, 1.0, 2.0]),
+            Some(PriceStats {
+                count: 3,
+                median: 2.0,
+                min: 1.0,
+                max: 3.0
+            })
+        );
+        assert_eq!(
+            PriceStats::from_prices(vec![4.0, 1.0, 2.0, 3.0])
+                .unwrap()
+                .median,
+            2.5
+        );
+    }
+
+    #[test]
+    fn records_creation_removal_and_expiry() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let removed = insert(&db, listing("Rough Cloth (T1)", "Hex Coin"));
+        let expired = insert(&db, listing("Rough Cloth (T1)", "Hex Coin"));
+        store.delete(removed.id, 1).unwrap();
+        db.execute(
+            "UPDATE listings SET expires_at = datetime('now', '-1 hour') WHERE id = ?",
+            params![expired.id],
+        )
+        .unwrap();
+        store.delete_expired().unwrap();
+        assert_eq!(events(&db, removed.id), vec!["created", "removed"]);
+        assert_eq!(events(&db, expired.id), vec!["created", "expired"]);
+    }
+
+    #[test]
+    fn edited_prices_are_recorded() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let listing = insert(&db, listing("Rough Cloth (T1)", "Hex Coin"));
+        store
+            .update(&Listing {
+                description: "still here".to_string(),
+                ..listing.clone()
+            })
+            .unwrap();
+        assert_eq!(events(&db, listing.id), vec!["created"]);
+
+        store
+            .update(&Listing {
+                request_quantity: 60,
+                ..listing.clone()
+            })
+            .unwrap();
+        assert_eq!(events(&db, listing.id), vec!["created", "edited"]);
+        let mut prices = HistoryStore::new(&db)
+            .prices(
+                item_id("Rough Cloth (T1)"),
+                item_id("Hex Coin"),
+                7,
+                Market::Public,
+            )
+            .unwrap();
+        prices.sort_by(|a, b| a.total_cmp(b));
+        assert_eq!(prices, vec![60.0, 100.0]);
+    }
+
+    #[test]
+    fn prices_from_both_sides_of_the_market() {
+        let db = test_db();
+        insert(&db, listing("Rough Cloth (T1)", "Hex Coin"));
+        post(
+            &db,
+            (2, "Rough Cloth (T1)"),
+            (100, "Hex Coin"),
+            ListingOverrides::default(),
+        );
+        post(
+            &db,
+            (80, "Hex Coin"),
+            (1, "Rough Cloth (T1)"),
+            ListingOverrides::default(),
+        );
+        post(
+            &db,
+            (1, "Rough Cloth (T1)"),
+            (1, "Fine Geode (T4)"),
+            ListingOverrides::default(),
+        );
+        let old = post(
+            &db,
+            (1, "Rough Cloth (T1)"),
+            (1000, "Hex Coin"),
+            ListingOverrides::default(),
+        );
+        db.execute(
+            "UPDATE listing_history SET timestamp = datetime('now', '-10 days') WHERE listing_id = ?",
+            params![old.id],
+        )
+        .unwrap();
+
+        let history = HistoryStore::new(&db);
+        let mut prices = history
+            .prices(
+                item_id("Rough Cloth (T1)"),
+                item_id("Hex Coin"),
+                7,
+                Market::Public,
+            )
+            .unwrap();
+        prices.sort_by(|a, b| a.total_cmp(b));
+        assert_eq!(prices, vec![50.0, 80.0, 100.0]);
+        assert_eq!(
+            history
+                .prices(
+                    item_id("Rough Cloth (T1)"),
+                    item_id("Hex Coin"),
+                    30,
+                    Market::Public
+                )
+                .unwrap()
+                .len(),
+            4
+        );
+        assert_eq!(
+            history
+                .counter_items(item_id("Rough Cloth (T1)"), 7, Market::Public)
+                .unwrap(),
+            vec![(item_id("Hex Coin"), 3), (item_id("Fine Geode (T4)"), 1)]
+        );
+    }
+
+    #[test]
+    fn prices_stay_in_their_market() {
+        let db = test_db();
+        GuildStore::new(&db)
+            .set(&GuildSettings {
+                guild_id: 10,
+                private_market: true,
+                include_public: true,
+            })
+            .unwrap();
+        insert(&db, listing("Rough Cloth (T1)", "Hex Coin"));
+        post(
+            &db,
+            (1, "Rough Cloth (T1)"),
+            (500, "Hex Coin"),
+            ListingOverrides {
+                guild_id: Some(10),
+                ..Default::default()
+            },
+        );
+
+        let history = HistoryStore::new(&db);
+        let prices = |market| {
+            let mut prices = history
+                .prices(item_id("Rough Cloth (T1)"), item_id("Hex Coin"), 7, market)
+                .unwrap();
+            prices.sort_by(|a, b| a.total_cmp(b));
+            prices
+        };
+        assert_eq!(prices(Market::Public), vec![100.0]);
+        let private = Market::Private {
+            guild_id: 10,
+            include_public: true,
+        };
+        assert_eq!(prices(private), vec![100.0, 500.0]);
+        assert_eq!(
+            history
+                .counter_items(item_id("Rough Cloth (T1)"), 7, Market::Public)
+                .unwrap(),
+            vec![(item_id("Hex Coin"), 1)]
+        );
+    }
+
+    #[test]
+    fn counter_items_skip_items_without_an_id() {
+        let db = test_db();
+        insert(&db, listing("Rough Cloth (T1)", "Hex Coin"));
+        // Posted before item IDs, for an item the catalog never had
+        insert(
+            &db,
+            Listing {
+                request_item: "Old Trinket".to_string(),
+                request_item_id: None,
+                ..listing("Rough Cloth (T1)", "Hex Coin")
+            },
+        );
+        assert_eq!(
+            HistoryStore::new(&db)
+                .counter_items(item_id("Rough Cloth (T1)"), 7, Market::Public)
+                .unwrap(),
+            vec![(item_id("Hex Coin"), 1)]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+/// How closely an item name matches a query, best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MatchKind {
+    Exact,
+    Prefix,
+    WordStart,
+    Substring,
+    /// Within a few typos of the start of the name or one of its words
+    Fuzzy(usize),
+}
+
+/// Item names prepared for ranked, typo-tolerant lookups
+#[derive(Debug, Clone, Default)]
+pub struct ItemIndex {
+    /// Lowercased & original names, sorted by lowercased name
+    entries: Vec<(String, String)>,
+}
+
+impl ItemIndex {
+    pub fn new<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut entries = names
+            .into_iter()
+            .map(|name| {
+                let name = name.into();
+                (name.to_lowercase(), name)
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries.dedup();
+        ItemIndex { entries }
+    }
+
+    /// Up to `limit` names matching `query`, ranked exact > prefix > word start > substring >
+    /// fewest typos, then shortest & alphabetical so results are stable
+    pub fn search(&self, query: &str, limit: usize) -> Vec<&str> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return self
+                .entries
+                .iter()
+                .take(limit)
+                .map(|(_, name)| name.as_str())
+                .collect();
+        }
+        let mut ranked = self
+            .entries
+            .iter()
+            .filter_map(|(lowercased, name)| {
+                match_kind(lowercased, &query)
+                    .map(|kind| (kind, lowercased.chars().count(), name.as_str()))
+            })
+            .collect::<Vec<_>>();
+        // Entries are already alphabetical, so a stable sort keeps that as the last tiebreak
+        ranked.sort_by_key(|(kind, length, _)| (*kind, *length));
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(_, _, name)| name)
+            .collect()
+    }
+}
+
+fn match_kind(name: &str, query: &str) -> Option<MatchKind> {
+    if name == query {
+        return Some(MatchKind::Exact);
+    }
+    if name.starts_with(query) {
+        return Some(MatchKind::Prefix);
+    }
+    let mut found = false;
+    for (index, _) in name.match_indices(query) {
+        found = true;
+        if is_word_start(name, index) {
+            return Some(MatchKind::WordStart);
+        }
+    }
+    if found {
+        return Some(MatchKind::Substring);
+    }
+
+    let query = query.chars().collect::<Vec<_>>();
+    let max_typos = match query.len() {
+        0..=3 => return None,
+        4..=7 => 1,
+        _ => 2,
+    };
+    let name = name.chars().collect::<Vec<_>>();
+    // Compare against the start of each word, allowing the typos to add or drop a character
+    (0..name.len())
+        .filter(|&start| start == 0 || !name[start - 1].is_alphanumeric())
+        .flat_map(|start| {
+            let name = &name;
+            let query = &query;
+            (query.len().saturating_sub(1)..=query.len() + 1)
+                .filter(move |&length| start + length <= name.len())
+                .map(move |length| edit_distance(&name[start..start + length], query))
+        })
+        .min()
+        .filter(|&typos| typos <= max_typos)
+        .map(MatchKind::Fuzzy)
+}
+
+fn is_word_start(name: &str, index: usize) -> bool {
+    name[..index]
+        .chars()
+        .next_back()
+        .is_none_or(|previous| !previous.is_alphanumeric())
+}
+
+/// Edits needed to turn `a` into `b`, counting swapped neighbours as a single edit
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            row[j] = substitution.min(rows[i - 1][j] + 1).min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> ItemIndex {
+        ItemIndex::new([
+            "Rough Cloth Strip (T1)",
+            "Rough Cloth (T1)",
+            "Fine Cloth (T3)",
+            "Clothing Rack",
+            "Hex Coin",
+            "Rough Wood Log (T1)",
+        ])
+    }
+
+    #[test]
+    fn ranks_prefix_then_word_start_then_substring() {
+        assert_eq!(
+            index().search("rough cloth", 10),
+            vec!["Rough Cloth (T1)", "Rough Cloth Strip (T1)"]
+        );
+        assert_eq!(
+            index().search("cloth", 10),
+            vec![
+                "Clothing Rack",
+                "Fine Cloth (T3)",
+                "Rough Cloth (T1)",
+                "Rough Cloth Strip (T1)"
+            ]
+        );
+        assert_eq!(index().search("oth", 10).len(), 4);
+        assert_eq!(
+            index().search("", 2),
+            vec!["Clothing Rack", "Fine Cloth (T3)"]
+        );
+    }
+
+    #[test]
+    fn tolerates_typos() {
+        assert_eq!(
+            index().search("ruogh", 3),
+            vec![
+                "Rough Cloth (T1)",
+                "Rough Wood Log (T1)",
+                "Rough Cloth Strip (T1)"
+            ]
+        );
+        assert_eq!(index().search("hex cion", 10), vec!["Hex Coin"]);
+        assert_eq!(index().search("xyz", 10), Vec::<&str>::new());
+    }
+}
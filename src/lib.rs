@@ -0,0 +1,17 @@
+pub mod catalog;
+pub mod db;
+pub mod feedback;
+pub mod guilds;
+pub mod history;
+pub mod item_index;
+pub mod listings;
+pub mod matching;
+pub mod migrations;
+pub mod preferences;
+pub mod trades;
+pub mod watches;
+
+#[cfg(test)]
+mod test_support;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -0,0 +1,1088 @@
+use crate::guilds::{Market, MARKET_CONDITION};
+use crate::history::{self, ListingEvent};
+use crate::Error;
+use rusqlite::{named_params, params, Connection, OptionalExtension, Row};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub id: i32,
+    pub offer_quantity: i32,
+    /// Display name of the offered item
+    pub offer_item: String,
+    /// Catalog ID of the offered item, missing for old listings of items the catalog never had
+    pub offer_item_id: Option<i64>,
+    pub request_quantity: i32,
+    pub request_item: String,
+    pub request_item_id: Option<i64>,
+    pub location_north: i32,
+    pub location_east: i32,
+    pub user: String,
+    /// Discord ID of the owner, missing for old listings that haven't been claimed yet
+    pub user_id: Option<u64>,
+    /// Guild the listing was posted in, missing for listings posted in DMs or before guilds were
+    /// tracked
+    pub guild_id: Option<u64>,
+    pub offer_count: i32,
+    pub description: String,
+    /// Whole days since the listing was posted or last renewed
+    pub renewed_days_ago: i64,
+    /// Distance from the searched location, only set on search results
+    pub distance: Option<f64>,
+}
+
+/// Longest description a listing can have
+pub const MAX_DESCRIPTION_LENGTH: usize = 300;
+
+/// Listing fields users set themselves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListingField {
+    /// The offered & requested items
+    Items,
+    OfferQuantity,
+    RequestQuantity,
+    OfferCount,
+    Description,
+}
+
+impl ListingField {
+    pub const ALL: &'static [ListingField] = &[
+        ListingField::Items,
+        ListingField::OfferQuantity,
+        ListingField::RequestQuantity,
+        ListingField::OfferCount,
+        ListingField::Description,
+    ];
+}
+
+impl Listing {
+    /// Check `fields` of the listing, returning a message for the user about the first invalid one
+    pub fn validate(&self, fields: &[ListingField]) -> Result<(), String> {
+        for field in fields {
+            let error_message = match field {
+                ListingField::Items if self.offer_item == self.request_item => {
+                    "Offered item cannot be the same as the requested item".to_string()
+                }
+                ListingField::OfferQuantity if self.offer_quantity < 1 => {
+                    "Offer quantity must be at least 1".to_string()
+                }
+                ListingField::RequestQuantity if self.request_quantity < 1 => {
+                    "Request quantity must be at least 1".to_string()
+                }
+                ListingField::OfferCount if self.offer_count < 1 => {
+                    "Offer count must be at least 1".to_string()
+                }
+                ListingField::Description if self.description.len() > MAX_DESCRIPTION_LENGTH => {
+                    format!(
+                        "Description must be {} characters or less",
+                        MAX_DESCRIPTION_LENGTH
+                    )
+                }
+                _ => continue,
+            };
+            return Err(format!("Invalid listing: {}", error_message));
+        }
+        Ok(())
+    }
+
+    /// Amount of the requested item asked per unit of the offered item
+    pub fn unit_price(&self) -> f64 {
+        self.request_quantity as f64 / self.offer_quantity as f64
+    }
+
+    /// Amount of the counter item exchanged per unit of the item with ID `item_id`, if this
+    /// listing trades it
+    pub fn price_of(&self, item_id: i64) -> Option<f64> {
+        if self.offer_item_id == Some(item_id) {
+            Some(self.unit_price())
+        } else if self.request_item_id == Some(item_id) {
+            Some(self.offer_quantity as f64 / self.request_quantity as f64)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ItemQuery {
+    SellingItem,
+    BuyingItem,
+}
+
+/// How the distance between two locations is measured
+#[derive(Debug, Clone, Copy, PartialEq, Default, poise::ChoiceParameter)]
+pub enum DistanceMetric {
+    /// Furthest of the north & east distances, searching a square
+    #[default]
+    #[name = "square"]
+    Square,
+    /// Straight line distance, searching a circle
+    #[name = "circle"]
+    Circle,
+    /// Straight line distance of at least `min_distance`, searching a ring
+    #[name = "ring"]
+    Ring,
+}
+
+/// Limits on the price of the searched item in terms of a counter item.
+/// Listings trading the searched item for anything other than the counter item are excluded.
+#[derive(Debug, Clone, Default)]
+pub struct PriceFilter {
+    pub counter_item_id: i64,
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+}
+
+/// Order of search results
+#[derive(Debug, Clone, Copy, PartialEq, Default, poise::ChoiceParameter)]
+pub enum SortOrder {
+    /// Closest first
+    #[default]
+    #[name = "distance"]
+    Distance,
+    /// Best rate for the searched item first: cheapest sellers, or buyers paying the most
+    #[name = "unit price"]
+    UnitPrice,
+    /// Most recently posted or renewed first
+    #[name = "newest"]
+    Newest,
+    /// Largest stock first
+    #[name = "largest stock"]
+    Stock,
+}
+
+/// Filters for finding listings around a location
+#[derive(Debug, Clone, Default)]
+pub struct ListingSearch {
+    pub location_north: i32,
+    pub location_east: i32,
+    /// Maximum distance from the location
+    pub distance: i32,
+    pub metric: DistanceMetric,
+    /// Minimum distance from the location, only used by `DistanceMetric::Ring`
+    pub min_distance: Option<i32>,
+    /// Only listings selling or buying the item with this ID
+    pub item: Option<(i64, ItemQuery)>,
+    /// Only listings posted or renewed within this many days
+    pub max_age_days: Option<i32>,
+    /// Only listings with a price for `item` in this range, ignored if `item` is `None`
+    pub price: Option<PriceFilter>,
+    pub sort: SortOrder,
+    /// Only listings visible in this market
+    pub market: Market,
+}
+
+/// Columns selected for every listing query, in the order `listing_from_row` reads them. Item
+/// names come from the catalog, falling back to the name the listing was posted with
+const LISTING_COLUMNS: &str = "id, sale_quantity, COALESCE((SELECT display_name FROM items WHERE items.id = sale_item_id), sale_item), sale_item_id, buy_quantity, COALESCE((SELECT display_name FROM items WHERE items.id = buy_item_id), buy_item), buy_item_id, location_north, location_east, username, offer_count, description, CAST(julianday('now') - julianday(timestamp) AS INTEGER), user_id, guild_id";
+
+fn listing_from_row(row: &Row) -> rusqlite::Result<Listing> {
+    Ok(Listing {
+        id: row.get(0)?,
+        offer_quantity: row.get(1)?,
+        offer_item: row.get(2)?,
+        offer_item_id: row.get(3)?,
+        request_quantity: row.get(4)?,
+        request_item: row.get(5)?,
+        request_item_id: row.get(6)?,
+        location_north: row.get(7)?,
+        location_east: row.get(8)?,
+        user: row.get(9)?,
+        offer_count: row.get(10)?,
+        description: row.get(11)?,
+        renewed_days_ago: row.get(12)?,
+        user_id: row.get(13)?,
+        guild_id: row.get(14)?,
+        distance: None,
+    })
+}
+
+/// All reads & writes of the listings table
+pub struct ListingStore<'a> {
+    db: &'a Connection,
+}
+
+impl<'a> ListingStore<'a> {
+    pub fn new(db: &'a Connection) -> Self {
+        ListingStore { db }
+    }
+
+    /// Insert a new listing that expires after `lifetime_days`, returning it with its assigned ID
+    pub fn insert(&self, listing: Listing, lifetime_days: i64) -> Result<Listing, Error> {
+        let tx = self.db.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO listings (sale_quantity, sale_item, sale_item_id, buy_quantity, buy_item, buy_item_id, location_north, location_east, username, timestamp, offer_count, description, user_id, guild_id, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP, ?, ?, ?, ?, datetime('now', ?))",
+            params![
+                listing.offer_quantity,
+                listing.offer_item,
+                listing.offer_item_id,
+                listing.request_quantity,
+                listing.request_item,
+                listing.request_item_id,
+                listing.location_north,
+                listing.location_east,
+                listing.user,
+                listing.offer_count,
+                listing.description,
+                listing.user_id,
+                listing.guild_id,
+                format!("+{} days", lifetime_days),
+            ],
+        )?;
+        let id = tx.last_insert_rowid() as i32;
+        history::record(&tx, ListingEvent::Created, "id = ?", params![id])?;
+        tx.commit()?;
+        Ok(Listing {
+            id,
+            renewed_days_ago: 0,
+            ..listing
+        })
+    }
+
+    /// Overwrite the quantities, stock, description & location of a listing owned by its `user_id`,
+    /// recording the new price in the history if the quantities changed. Returns false if no such
+    /// listing exists.
+    pub fn update(&self, listing: &Listing) -> Result<bool, Error> {
+        let tx = self.db.unchecked_transaction()?;
+        let repriced: Option<bool> = tx
+            .query_row(
+                "SELECT sale_quantity != ? OR buy_quantity != ? FROM listings WHERE id = ? AND user_id = ?",
+                params![
+                    listing.offer_quantity,
+                    listing.request_quantity,
+                    listing.id,
+                    listing.user_id,
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(repriced) = repriced else {
+            return Ok(false);
+        };
+        tx.execute(
+            "UPDATE listings
+            SET sale_quantity = ?, buy_quantity = ?, offer_count = ?, description = ?, location_north = ?, location_east = ?
+            WHERE id = ? AND user_id = ?",
+            params![
+                listing.offer_quantity,
+                listing.request_quantity,
+                listing.offer_count,
+                listing.description,
+                listing.location_north,
+                listing.location_east,
+                listing.id,
+                listing.user_id,
+            ],
+        )?;
+        if repriced {
+            history::record(&tx, ListingEvent::Edited, "id = ?", params![listing.id])?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// Delete a listing if it belongs to `user_id`. Returns false if no such listing exists.
+    pub fn delete(&self, listing_id: i32, user_id: u64) -> Result<bool, Error> {
+        let tx = self.db.unchecked_transaction()?;
+        history::record(
+            &tx,
+            ListingEvent::Removed,
+            "id = ? AND user_id = ?",
+            params![listing_id, user_id],
+        )?;
+        let deleted = tx.execute(
+            "DELETE FROM listings WHERE id = ? AND user_id = ?",
+            params![listing_id, user_id],
+        )?;
+        tx.commit()?;
+        Ok(deleted > 0)
+    }
+
+    /// Restart the lifetime of one of a user's listings, or all of them if `listing_id` is `None`.
+    /// Returns the number of listings renewed.
+    pub fn renew(
+        &self,
+        listing_id: Option<i32>,
+        user_id: u64,
+        lifetime_days: i64,
+    ) -> Result<usize, Error> {
+        Ok(self.db.execute(
+            "UPDATE listings
+            SET timestamp = CURRENT_TIMESTAMP, expires_at = datetime('now', ?1), expiry_notified = 0
+            WHERE (?2 IS NULL OR id = ?2) AND user_id = ?3",
+            params![format!("+{} days", lifetime_days), listing_id, user_id],
+        )?)
+    }
+
+    /// Get a listing by ID
+    pub fn get(&self, listing_id: i32) -> Result<Option<Listing>, Error> {
+        Ok(self
+            .db
+            .query_row(
+                &format!("SELECT {} FROM listings WHERE id = ?1", LISTING_COLUMNS),
+                params![listing_id],
+                listing_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Get a listing by ID if it's visible in `market`
+    pub fn get_in(&self, listing_id: i32, market: Market) -> Result<Option<Listing>, Error> {
+        let (market_guild, include_public) = market.params();
+        Ok(self
+            .db
+            .query_row(
+                &format!(
+                    "SELECT {} FROM listings WHERE id = :id AND {}",
+                    LISTING_COLUMNS, MARKET_CONDITION
+                ),
+                named_params! {
+                    ":id": listing_id,
+                    ":market_guild": market_guild,
+                    ":include_public": include_public,
+                },
+                listing_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Get all listings owned by a user
+    pub fn by_owner(&self, user_id: u64) -> Result<Vec<Listing>, Error> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM listings WHERE user_id = ?",
+            LISTING_COLUMNS
+        ))?;
+        let listings = stmt
+            .query_map(params![user_id], listing_from_row)?
+            .collect::<rusqlite::Result<Vec<Listing>>>()?;
+        Ok(listings)
+    }
+
+    /// Number of listings owned by a user
+    pub fn count_by_owner(&self, user_id: u64) -> Result<i32, Error> {
+        Ok(self.db.query_row(
+            "SELECT COUNT(*) FROM listings WHERE user_id = ?",
+            params![user_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Number of listings in `market` offering or requesting each item, by item ID
+    pub fn count_by_item(&self, market: Market) -> Result<HashMap<i64, i32>, Error> {
+        let (market_guild, include_public) = market.params();
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT item_id, COUNT(DISTINCT id) FROM (
+                SELECT id, sale_item_id AS item_id FROM listings WHERE {condition}
+                UNION ALL
+                SELECT id, buy_item_id AS item_id FROM listings WHERE {condition}
+            )
+            WHERE item_id IS NOT NULL
+            GROUP BY item_id",
+            condition = MARKET_CONDITION,
+        ))?;
+        let counts = stmt
+            .query_map(
+                named_params! {
+                    ":market_guild": market_guild,
+                    ":include_public": include_public,
+                },
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+            .collect::<rusqlite::Result<HashMap<i64, i32>>>()?;
+        Ok(counts)
+    }
+
+    /// Get listings within `search.distance` of a location, measured with `search.metric`
+    pub fn search(&self, search: &ListingSearch) -> Result<Vec<Listing>, Error> {
+        let (item, selling) = match &search.item {
+            Some((item, query)) => (Some(*item), *query == ItemQuery::SellingItem),
+            None => (None, false),
+        };
+        // Distances are compared squared since the bundled SQLite has no SQRT
+        let distance_squared = match search.metric {
+            DistanceMetric::Square => {
+                "MAX(ABS(location_north - :north), ABS(location_east - :east)) * MAX(ABS(location_north - :north), ABS(location_east - :east))"
+            }
+            DistanceMetric::Circle | DistanceMetric::Ring => {
+                "(location_north - :north) * (location_north - :north) + (location_east - :east) * (location_east - :east)"
+            }
+        };
+        // Price of the searched item, paid in the counter item
+        let (price, counter_item_column) = match &search.item {
+            Some((_, ItemQuery::BuyingItem)) => {
+                ("CAST(sale_quantity AS REAL) / buy_quantity", "sale_item_id")
+            }
+            _ => ("CAST(buy_quantity AS REAL) / sale_quantity", "buy_item_id"),
+        };
+        let order = match (search.sort, &search.item) {
+            (SortOrder::Distance, _) => "distance_squared, id".to_string(),
+            (SortOrder::UnitPrice, Some((_, ItemQuery::BuyingItem))) => {
+                format!("{} DESC, id", price)
+            }
+            (SortOrder::UnitPrice, _) => format!("{}, id", price),
+            (SortOrder::Newest, _) => "timestamp DESC, id DESC".to_string(),
+            (SortOrder::Stock, _) => "offer_count DESC, id".to_string(),
+        };
+        let price_filter = search.price.as_ref().filter(|_| item.is_some());
+        let min_distance = match search.metric {
+            DistanceMetric::Ring => search.min_distance.unwrap_or(0).max(0),
+            _ => 0,
+        };
+        let (market_guild, include_public) = search.market.params();
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {}, {} AS distance_squared
+            FROM listings
+            WHERE
+            distance_squared BETWEEN :min_distance * :min_distance AND :distance * :distance
+            AND (:item IS NULL OR (:selling AND sale_item_id = :item) OR (NOT :selling AND buy_item_id = :item))
+            AND (:max_age_days IS NULL OR julianday('now') - julianday(timestamp) <= :max_age_days)
+            AND (:counter_item IS NULL OR (
+                {counter_item_column} = :counter_item
+                AND (:min_price IS NULL OR {price} >= :min_price)
+                AND (:max_price IS NULL OR {price} <= :max_price)
+            ))
+            AND {}
+            ORDER BY {}",
+            LISTING_COLUMNS,
+            distance_squared,
+            MARKET_CONDITION,
+            order,
+            counter_item_column = counter_item_column,
+            price = price,
+        ))?;
+        let listings = stmt
+            .query_map(
+                named_params! {
+                    ":north": search.location_north,
+                    ":east": search.location_east,
+                    ":distance": search.distance,
+                    ":min_distance": min_distance,
+                    ":item": item,
+                    ":selling": selling,
+                    ":max_age_days": search.max_age_days,
+                    ":counter_item": price_filter.map(|filter| filter.counter_item_id),
+                    ":min_price": price_filter.and_then(|filter| filter.min_price),
+                    ":max_price": price_filter.and_then(|filter| filter.max_price),
+                    ":market_guild": market_guild,
+                    ":include_public": include_public,
+                },
+                |row| {
+                    Ok(Listing {
+                        distance: Some((row.get::<_, i64>(15)? as f64).sqrt()),
+                        ..listing_from_row(row)?
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<Listing>>>()?;
+        Ok(listings)
+    }
+
+    /// Give a user ownership of listings posted under their name before owners were tracked by ID
+    pub fn claim_legacy(&self, user_id: u64, username: &str) -> Result<usize, Error> {
+        Ok(self.db.execute(
+            "UPDATE listings SET user_id = ? WHERE user_id IS NULL AND username = ?",
+            params![user_id, username],
+        )?)
+    }
+
+    /// Number of listings that don't have an owner ID yet
+    pub fn count_unclaimed(&self) -> Result<i32, Error> {
+        Ok(self.db.query_row(
+            "SELECT COUNT(*) FROM listings WHERE user_id IS NULL",
+            (),
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Give listings from before expiry existed a full lifetime from their creation
+    pub fn backfill_expiry(&self, lifetime_days: i64) -> Result<usize, Error> {
+        Ok(self.db.execute(
+            "UPDATE listings SET expires_at = datetime(timestamp, ?1) WHERE expires_at IS NULL",
+            params![format!("+{} days", lifetime_days)],
+        )?)
+    }
+
+    /// Delete listings past their expiry time
+    pub fn delete_expired(&self) -> Result<usize, Error> {
+        let tx = self.db.unchecked_transaction()?;
+        history::record(
+            &tx,
+            ListingEvent::Expired,
+            "expires_at <= CURRENT_TIMESTAMP",
+            (),
+        )?;
+        let deleted = tx.execute(
+            "DELETE FROM listings WHERE expires_at <= CURRENT_TIMESTAMP",
+            (),
+        )?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Get listings expiring within a day whose owners haven't been warned yet
+    pub fn expiring(&self) -> Result<Vec<Listing>, Error> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {}
+            FROM listings
+            WHERE expiry_notified = 0 AND user_id IS NOT NULL AND expires_at <= datetime('now', '+1 day')",
+            LISTING_COLUMNS
+        ))?;
+        let expiring = stmt
+            .query_map((), listing_from_row)?
+            .collect::<rusqlite::Result<Vec<Listing>>>()?;
+        Ok(expiring)
+    }
+
+    /// Record that the owner of a listing has been warned it's expiring
+    pub fn mark_expiry_notified(&self, listing_id: i32) -> Result<(), Error> {
+        self.db.execute(
+            "UPDATE listings SET expiry_notified = 1 WHERE id = ?",
+            params![listing_id],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guilds::{GuildSettings, GuildStore};
+    use crate::test_support::{self, item_id, test_db, TRADER as OWNER};
+
+    const OTHER: u64 = 2;
+
+    fn listing(offer_item: &str, request_item: &str, north: i32, east: i32) -> Listing {
+        Listing {
+            location_north: north,
+            location_east: east,
+            offer_count: 3,
+            ..test_support::listing(offer_item, request_item)
+        }
+    }
+
+    fn search(north: i32, east: i32, distance: i32) -> ListingSearch {
+        ListingSearch {
+            location_north: north,
+            location_east: east,
+            distance,
+            ..Default::default()
+        }
+    }
+
+    fn ids(listings: &[Listing]) -> Vec<i32> {
+        let mut ids = listings.iter().map(|l| l.id).collect::<Vec<i32>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn validates_only_given_fields() {
+        let same_items = listing("Hex Coin", "Hex Coin", 0, 0);
+        assert!(same_items.validate(ListingField::ALL).is_err());
+        assert!(same_items
+            .validate(&[ListingField::Description, ListingField::OfferCount])
+            .is_ok());
+        let long_description = Listing {
+            description: "a".repeat(MAX_DESCRIPTION_LENGTH + 1),
+            ..listing("Rough Cloth (T1)", "Hex Coin", 0, 0)
+        };
+        assert!(long_description
+            .validate(&[ListingField::Description])
+            .is_err());
+        assert!(long_description.validate(&[ListingField::Items]).is_ok());
+    }
+
+    #[test]
+    fn rejects_quantities_below_one() {
+        let valid = listing("Rough Cloth (T1)", "Hex Coin", 0, 0);
+        assert!(valid.validate(ListingField::ALL).is_ok());
+        // A negative quantity makes the unit price negative, undercutting every price limit
+        for invalid in [
+            Listing {
+                offer_quantity: -1,
+                request_quantity: 1000000,
+                ..valid.clone()
+            },
+            Listing {
+                request_quantity: 0,
+                ..valid.clone()
+            },
+            Listing {
+                offer_count: -5,
+                ..valid.clone()
+            },
+        ] {
+            assert!(invalid.validate(ListingField::ALL).is_err());
+        }
+    }
+
+    #[test]
+    fn insert_then_get() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let inserted = store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+        assert!(inserted.id > 0);
+        assert_eq!(store.get(inserted.id).unwrap(), Some(inserted));
+        assert_eq!(store.get(999).unwrap(), None);
+    }
+
+    #[test]
+    fn only_the_owner_can_update_or_delete() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let inserted = store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+
+        let stolen = Listing {
+            offer_count: 0,
+            user_id: Some(OTHER),
+            ..inserted.clone()
+        };
+        assert!(!store.update(&stolen).unwrap());
+        assert!(!store.delete(inserted.id, OTHER).unwrap());
+
+        let edited = Listing {
+            offer_count: 10,
+            description: "restocked".to_string(),
+            ..inserted.clone()
+        };
+        assert!(store.update(&edited).unwrap());
+        assert_eq!(store.get(inserted.id).unwrap(), Some(edited));
+
+        assert!(store.delete(inserted.id, OWNER).unwrap());
+        assert_eq!(store.get(inserted.id).unwrap(), None);
+    }
+
+    #[test]
+    fn lists_and_counts_by_owner() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+        store
+            .insert(listing("Fine Geode (T4)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+        store
+            .insert(
+                Listing {
+                    user_id: Some(OTHER),
+                    ..listing("Fine Geode (T4)", "Hex Coin", 0, 0)
+                },
+                5,
+            )
+            .unwrap();
+        assert_eq!(store.by_owner(OWNER).unwrap().len(), 2);
+        assert_eq!(store.count_by_owner(OWNER).unwrap(), 2);
+        assert_eq!(store.count_by_owner(OTHER).unwrap(), 1);
+    }
+
+    #[test]
+    fn search_is_bounded_by_distance() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let near = store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 100, 100), 5)
+            .unwrap();
+        let corner = store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 150, 50), 5)
+            .unwrap();
+        store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 300, 100), 5)
+            .unwrap();
+        let found = store.search(&search(100, 100, 50)).unwrap();
+        assert_eq!(ids(&found), vec![near.id, corner.id]);
+        let corner_distance = found.iter().find(|l| l.id == corner.id).unwrap().distance;
+        assert_eq!(corner_distance, Some(50.0));
+    }
+
+    #[test]
+    fn circle_search_excludes_corners() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let near = store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 30, 40), 5)
+            .unwrap();
+        store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 50, 50), 5)
+            .unwrap();
+        let found = store
+            .search(&ListingSearch {
+                metric: DistanceMetric::Circle,
+                ..search(0, 0, 50)
+            })
+            .unwrap();
+        assert_eq!(ids(&found), vec![near.id]);
+        assert_eq!(found[0].distance, Some(50.0));
+    }
+
+    #[test]
+    fn ring_search_excludes_the_middle() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 10, 0), 5)
+            .unwrap();
+        let ring = store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 0, -60), 5)
+            .unwrap();
+        store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 0, 120), 5)
+            .unwrap();
+        let found = store
+            .search(&ListingSearch {
+                metric: DistanceMetric::Ring,
+                min_distance: Some(50),
+                ..search(0, 0, 100)
+            })
+            .unwrap();
+        assert_eq!(ids(&found), vec![ring.id]);
+    }
+
+    #[test]
+    fn search_filters_by_item_side() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let selling = store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+        let buying = store
+            .insert(listing("Hex Coin", "Rough Cloth (T1)", 0, 0), 5)
+            .unwrap();
+        store
+            .insert(listing("Fine Geode (T4)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+
+        let sellers = store
+            .search(&ListingSearch {
+                item: Some((item_id("Rough Cloth (T1)"), ItemQuery::SellingItem)),
+                ..search(0, 0, 10)
+            })
+            .unwrap();
+        assert_eq!(ids(&sellers), vec![selling.id]);
+
+        let buyers = store
+            .search(&ListingSearch {
+                item: Some((item_id("Rough Cloth (T1)"), ItemQuery::BuyingItem)),
+                ..search(0, 0, 10)
+            })
+            .unwrap();
+        assert_eq!(ids(&buyers), vec![buying.id]);
+
+        assert_eq!(store.search(&search(0, 0, 10)).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn search_filters_by_age_and_renew_resets_it() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let fresh = store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+        let stale = store
+            .insert(listing("Fine Geode (T4)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+        db.execute(
+            "UPDATE listings SET timestamp = datetime('now', '-3 days') WHERE id = ?",
+            params![stale.id],
+        )
+        .unwrap();
+        assert_eq!(store.get(stale.id).unwrap().unwrap().renewed_days_ago, 3);
+
+        let recent = ListingSearch {
+            max_age_days: Some(1),
+            ..search(0, 0, 10)
+        };
+        assert_eq!(ids(&store.search(&recent).unwrap()), vec![fresh.id]);
+
+        assert_eq!(store.renew(Some(stale.id), OTHER, 5).unwrap(), 0);
+        assert_eq!(store.renew(Some(stale.id), OWNER, 5).unwrap(), 1);
+        assert_eq!(
+            ids(&store.search(&recent).unwrap()),
+            vec![fresh.id, stale.id]
+        );
+        assert_eq!(store.renew(None, OWNER, 5).unwrap(), 2);
+    }
+
+    #[test]
+    fn search_sorts_results() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let far_cheap = store
+            .insert(
+                Listing {
+                    request_quantity: 50,
+                    offer_count: 1,
+                    ..listing("Rough Cloth (T1)", "Hex Coin", 40, 0)
+                },
+                5,
+            )
+            .unwrap();
+        let near_pricey = store
+            .insert(
+                Listing {
+                    request_quantity: 200,
+                    offer_count: 9,
+                    ..listing("Rough Cloth (T1)", "Hex Coin", 10, 0)
+                },
+                5,
+            )
+            .unwrap();
+        let middle_old = store
+            .insert(
+                Listing {
+                    request_quantity: 100,
+                    offer_count: 5,
+                    ..listing("Rough Cloth (T1)", "Hex Coin", 20, 0)
+                },
+                5,
+            )
+            .unwrap();
+        db.execute(
+            "UPDATE listings SET timestamp = datetime('now', '-1 day') WHERE id = ?",
+            params![middle_old.id],
+        )
+        .unwrap();
+        db.execute(
+            "UPDATE listings SET timestamp = datetime('now', '-1 hour') WHERE id = ?",
+            params![far_cheap.id],
+        )
+        .unwrap();
+
+        let sorted = |sort| {
+            store
+                .search(&ListingSearch {
+                    sort,
+                    item: Some((item_id("Rough Cloth (T1)"), ItemQuery::SellingItem)),
+                    ..search(0, 0, 100)
+                })
+                .unwrap()
+                .iter()
+                .map(|l| l.id)
+                .collect::<Vec<i32>>()
+        };
+        assert_eq!(
+            sorted(SortOrder::Distance),
+            vec![near_pricey.id, middle_old.id, far_cheap.id]
+        );
+        assert_eq!(
+            sorted(SortOrder::UnitPrice),
+            vec![far_cheap.id, middle_old.id, near_pricey.id]
+        );
+        assert_eq!(
+            sorted(SortOrder::Newest),
+            vec![near_pricey.id, far_cheap.id, middle_old.id]
+        );
+        assert_eq!(
+            sorted(SortOrder::Stock),
+            vec![near_pricey.id, middle_old.id, far_cheap.id]
+        );
+    }
+
+    #[test]
+    fn buyers_sort_by_highest_payment() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let low = store
+            .insert(
+                Listing {
+                    offer_quantity: 50,
+                    ..listing("Hex Coin", "Rough Cloth (T1)", 0, 0)
+                },
+                5,
+            )
+            .unwrap();
+        let high = store
+            .insert(
+                Listing {
+                    offer_quantity: 150,
+                    ..listing("Hex Coin", "Rough Cloth (T1)", 0, 0)
+                },
+                5,
+            )
+            .unwrap();
+        let found = store
+            .search(&ListingSearch {
+                sort: SortOrder::UnitPrice,
+                item: Some((item_id("Rough Cloth (T1)"), ItemQuery::BuyingItem)),
+                ..search(0, 0, 100)
+            })
+            .unwrap();
+        assert_eq!(
+            found.iter().map(|l| l.id).collect::<Vec<i32>>(),
+            vec![high.id, low.id]
+        );
+    }
+
+    #[test]
+    fn unit_price_from_either_side() {
+        let selling = Listing {
+            offer_quantity: 4,
+            request_quantity: 100,
+            ..listing("Rough Cloth (T1)", "Hex Coin", 0, 0)
+        };
+        assert_eq!(selling.unit_price(), 25.0);
+        assert_eq!(selling.price_of(item_id("Rough Cloth (T1)")), Some(25.0));
+        assert_eq!(selling.price_of(item_id("Hex Coin")), Some(0.04));
+        assert_eq!(selling.price_of(item_id("Fine Geode (T4)")), None);
+    }
+
+    #[test]
+    fn search_filters_by_price_in_counter_item() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let cheap = store
+            .insert(
+                Listing {
+                    request_quantity: 50,
+                    ..listing("Rough Cloth (T1)", "Hex Coin", 0, 0)
+                },
+                5,
+            )
+            .unwrap();
+        let pricey = store
+            .insert(
+                Listing {
+                    request_quantity: 200,
+                    ..listing("Rough Cloth (T1)", "Hex Coin", 0, 0)
+                },
+                5,
+            )
+            .unwrap();
+        store
+            .insert(listing("Rough Cloth (T1)", "Fine Geode (T4)", 0, 0), 5)
+            .unwrap();
+        let buyer = store
+            .insert(
+                Listing {
+                    offer_quantity: 150,
+                    request_quantity: 1,
+                    ..listing("Hex Coin", "Rough Cloth (T1)", 0, 0)
+                },
+                5,
+            )
+            .unwrap();
+
+        let priced = |query, min_price, max_price| {
+            ids(&store
+                .search(&ListingSearch {
+                    item: Some((item_id("Rough Cloth (T1)"), query)),
+                    price: Some(PriceFilter {
+                        counter_item_id: item_id("Hex Coin"),
+                        min_price,
+                        max_price,
+                    }),
+                    ..search(0, 0, 10)
+                })
+                .unwrap())
+        };
+        assert_eq!(
+            priced(ItemQuery::SellingItem, None, None),
+            vec![cheap.id, pricey.id]
+        );
+        assert_eq!(
+            priced(ItemQuery::SellingItem, None, Some(100.0)),
+            vec![cheap.id]
+        );
+        assert_eq!(
+            priced(ItemQuery::SellingItem, Some(100.0), None),
+            vec![pricey.id]
+        );
+        assert_eq!(
+            priced(ItemQuery::BuyingItem, Some(100.0), None),
+            vec![buyer.id]
+        );
+        assert!(priced(ItemQuery::BuyingItem, None, Some(100.0)).is_empty());
+    }
+
+    #[test]
+    fn expiry_warns_once_then_deletes() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let expiring = store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+        let lasting = store
+            .insert(listing("Fine Geode (T4)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+        db.execute(
+            "UPDATE listings SET expires_at = datetime('now', '+1 hour') WHERE id = ?",
+            params![expiring.id],
+        )
+        .unwrap();
+
+        assert_eq!(ids(&store.expiring().unwrap()), vec![expiring.id]);
+        // Owners stay due a warning until one is delivered
+        assert_eq!(ids(&store.expiring().unwrap()), vec![expiring.id]);
+        store.mark_expiry_notified(expiring.id).unwrap();
+        assert!(store.expiring().unwrap().is_empty());
+        assert_eq!(store.delete_expired().unwrap(), 0);
+
+        db.execute(
+            "UPDATE listings SET expires_at = datetime('now', '-1 hour') WHERE id = ?",
+            params![expiring.id],
+        )
+        .unwrap();
+        assert_eq!(store.delete_expired().unwrap(), 1);
+        assert_eq!(store.get(expiring.id).unwrap(), None);
+        assert!(store.get(lasting.id).unwrap().is_some());
+    }
+
+    #[test]
+    fn legacy_listings_are_claimed_by_name() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        let legacy = store
+            .insert(
+                Listing {
+                    user_id: None,
+                    ..listing("Rough Cloth (T1)", "Hex Coin", 0, 0)
+                },
+                5,
+            )
+            .unwrap();
+        assert_eq!(store.count_unclaimed().unwrap(), 1);
+        assert_eq!(store.claim_legacy(OTHER, "someone else").unwrap(), 0);
+        assert_eq!(store.claim_legacy(OWNER, "trader").unwrap(), 1);
+        assert_eq!(store.count_unclaimed().unwrap(), 0);
+        assert!(store.delete(legacy.id, OWNER).unwrap());
+    }
+
+    #[test]
+    fn counts_listings_by_item() {
+        let db = test_db();
+        let store = ListingStore::new(&db);
+        store
+            .insert(listing("Rough Cloth (T1)", "Hex Coin", 0, 0), 5)
+            .unwrap();
+        store
+            .insert(listing("Hex Coin", "Rough Cloth (T1)", 0, 0), 5)
+            .unwrap();
+        store
+            .insert(
+                Listing {
+                    guild_id: Some(7),
+                    ..listing("Fine Geode (T4)", "Hex Coin", 0, 0)
+                },
+                5,
+            )
+            .unwrap();
+        let settings = GuildSettings {
+            guild_id: 7,
+            private_market: true,
+            include_public: false,
+        };
+        GuildStore::new(&db).set(&settings).unwrap();
+
+        let public = store.count_by_item(Market::Public).unwrap();
+        assert_eq!(public.get(&item_id("Rough Cloth (T1)")), Some(&2));
+        assert_eq!(public.get(&item_id("Hex Coin")), Some(&2));
+        assert_eq!(public.get(&item_id("Fine Geode (T4)")), None);
+        let private = store.count_by_item(settings.market()).unwrap();
+        assert_eq!(private.get(&item_id("Hex Coin")), Some(&1));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::guilds::GuildStore;
+use crate::listings::{
+    DistanceMetric, ItemQuery, Listing, ListingSearch, ListingStore, PriceFilter, SortOrder,
+};
+use crate::Error;
+use rusqlite::Connection;
+
+/// Find listings within `max_distance` of `listing` that trade the other way at a rate both sides
+/// accept, best rate first. A listing offering X for Y matches one offering Y for X when the
+/// counter listing asks no more X per Y than `listing` is willing to give. Listings in a private
+/// market only match others from the same guild.
+pub fn find_matches(
+    db: &Connection,
+    listing: &Listing,
+    max_distance: i32,
+) -> Result<Vec<Listing>, Error> {
+    // Items the catalog never had can't be matched
+    let (Some(offer_item_id), Some(request_item_id)) =
+        (listing.offer_item_id, listing.request_item_id)
+    else {
+        return Ok(Vec::new());
+    };
+    let matches = ListingStore::new(db).search(&ListingSearch {
+        location_north: listing.location_north,
+        location_east: listing.location_east,
+        distance: max_distance,
+        metric: DistanceMetric::Circle,
+        item: Some((request_item_id, ItemQuery::SellingItem)),
+        price: Some(PriceFilter {
+            counter_item_id: offer_item_id,
+            min_price: None,
+            max_price: Some(listing.offer_quantity as f64 / listing.request_quantity as f64),
+        }),
+        sort: SortOrder::UnitPrice,
+        market: GuildStore::new(db).listing_market(listing.guild_id)?,
+        ..Default::default()
+    })?;
+    Ok(matches
+        .into_iter()
+        .filter(|candidate| candidate.id != listing.id)
+        .filter(|candidate| listing.user_id.is_none() || candidate.user_id != listing.user_id)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{post, test_db, ListingOverrides};
+
+    fn ids(listings: &[Listing]) -> Vec<i32> {
+        listings.iter().map(|l| l.id).collect()
+    }
+
+    #[test]
+    fn matches_counter_listings_at_a_compatible_rate() {
+        let db = test_db();
+        // Selling cloth for 100 coins each
+        let seller = post(
+            &db,
+            (1, "Rough Cloth (T1)"),
+            (100, "Hex Coin"),
+            ListingOverrides::owned_by(1),
+        );
+        // Buying cloth for 120 coins each, 100 coins each & 80 coins each
+        let generous = post(
+            &db,
+            (120, "Hex Coin"),
+            (1, "Rough Cloth (T1)"),
+            ListingOverrides::owned_by(2),
+        );
+        let exact = post(
+            &db,
+            (200, "Hex Coin"),
+            (2, "Rough Cloth (T1)"),
+            ListingOverrides::owned_by(3),
+        );
+        post(
+            &db,
+            (80, "Hex Coin"),
+            (1, "Rough Cloth (T1)"),
+            ListingOverrides::owned_by(4),
+        );
+        // Wrong counter item
+        post(
+            &db,
+            (1, "Fine Geode (T4)"),
+            (1, "Rough Cloth (T1)"),
+            ListingOverrides::owned_by(5),
+        );
+
+        let matches = find_matches(&db, &seller, 100).unwrap();
+        assert_eq!(ids(&matches), vec![generous.id, exact.id]);
+
+        let matches = find_matches(&db, &generous, 100).unwrap();
+        assert_eq!(ids(&matches), vec![seller.id]);
+    }
+
+    #[test]
+    fn ignores_distant_and_own_listings() {
+        let db = test_db();
+        let seller = post(
+            &db,
+            (1, "Rough Cloth (T1)"),
+            (100, "Hex Coin"),
+            ListingOverrides::owned_by(1),
+        );
+        post(
+            &db,
+            (100, "Hex Coin"),
+            (1, "Rough Cloth (T1)"),
+            ListingOverrides::owned_by(1),
+        );
+        post(
+            &db,
+            (100, "Hex Coin"),
+            (1, "Rough Cloth (T1)"),
+            ListingOverrides {
+                location_north: Some(500),
+                ..ListingOverrides::owned_by(2)
+            },
+        );
+        assert!(find_matches(&db, &seller, 100).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,383 @@
+use crate::Error;
+use rusqlite::{Connection, Transaction};
+
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Schema migrations in the order they're applied. The database's `user_version` is the number of
+/// migrations that have already run, so new migrations must only ever be appended.
+/// Databases created before versioning have a `user_version` of 0 and may already have some of
+/// these changes applied, so every migration has to be safe to re-run.
+const MIGRATIONS: &[Migration] = &[
+    create_listings,
+    add_owner_and_expiry,
+    create_watches,
+    create_listing_history,
+    create_trades,
+    create_feedback,
+    create_user_preferences,
+    create_saved_locations,
+    add_guild_markets,
+    create_items,
+    add_market_to_history_and_watches,
+    add_item_ids,
+];
+
+/// Schema version this build expects
+pub const SCHEMA_VERSION: i32 = MIGRATIONS.len() as i32;
+
+/// Bring the database up to date, applying each pending migration in its own transaction
+pub fn migrate(db: &mut Connection) -> Result<(), Error> {
+    let version = schema_version(db)?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "Database schema version {} is newer than the latest known version {}",
+            version, SCHEMA_VERSION
+        )
+        .into());
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = db.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index as i32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Current schema version of the database
+pub fn schema_version(db: &Connection) -> rusqlite::Result<i32> {
+    db.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+fn create_listings(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS listings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            sale_quantity int,
+            sale_item text,
+            buy_quantity int,
+            buy_item text,
+            location_north int,
+            location_east int,
+            username text,
+            offer_count int,
+            description text DEFAULT '',
+            timestamp timestamp DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn add_owner_and_expiry(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "listings", "user_id", "int")?;
+    add_column_if_missing(tx, "listings", "expires_at", "timestamp")?;
+    add_column_if_missing(tx, "listings", "expiry_notified", "int DEFAULT 0")?;
+    Ok(())
+}
+
+fn create_watches(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS watches (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id int NOT NULL,
+            item text NOT NULL,
+            location_north int NOT NULL,
+            location_east int NOT NULL,
+            distance int NOT NULL,
+            counter_item text,
+            max_price real,
+            timestamp timestamp DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS watches_item ON watches (item)",
+        (),
+    )?;
+    Ok(())
+}
+
+fn create_listing_history(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS listing_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            listing_id int NOT NULL,
+            event text NOT NULL,
+            sale_quantity int,
+            sale_item text,
+            buy_quantity int,
+            buy_item text,
+            user_id int,
+            timestamp timestamp DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS listing_history_items ON listing_history (sale_item, buy_item)",
+        (),
+    )?;
+    // Seed the history with the listings that are already up
+    tx.execute(
+        "INSERT INTO listing_history (listing_id, event, sale_quantity, sale_item, buy_quantity, buy_item, user_id, timestamp)
+        SELECT id, 'created', sale_quantity, sale_item, buy_quantity, buy_item, user_id, timestamp
+        FROM listings
+        WHERE id NOT IN (SELECT listing_id FROM listing_history)",
+        (),
+    )?;
+    Ok(())
+}
+
+fn create_trades(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS trades (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            listing_id int NOT NULL,
+            owner_id int NOT NULL,
+            counterparty_id int,
+            sale_quantity int NOT NULL,
+            sale_item text NOT NULL,
+            buy_quantity int NOT NULL,
+            buy_item text NOT NULL,
+            quantity int NOT NULL,
+            timestamp timestamp DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn create_feedback(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS feedback (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            rater_id int NOT NULL,
+            user_id int NOT NULL,
+            listing_id int NOT NULL,
+            score int NOT NULL,
+            comment text NOT NULL DEFAULT '',
+            timestamp timestamp DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (rater_id, listing_id)
+        )",
+        (),
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS feedback_user ON feedback (user_id)",
+        (),
+    )?;
+    Ok(())
+}
+
+fn create_user_preferences(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS user_preferences (
+            user_id int PRIMARY KEY,
+            home_north int,
+            home_east int,
+            search_radius int
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn create_saved_locations(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS saved_locations (
+            user_id int NOT NULL,
+            name text NOT NULL COLLATE NOCASE,
+            location_north int NOT NULL,
+            location_east int NOT NULL,
+            PRIMARY KEY (user_id, name)
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn add_guild_markets(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "listings", "guild_id", "int")?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS guild_settings (
+            guild_id int PRIMARY KEY,
+            private_market int NOT NULL DEFAULT 0,
+            include_public int NOT NULL DEFAULT 0
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn create_items(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind text NOT NULL,
+            name text NOT NULL,
+            tier int,
+            display_name text NOT NULL
+        )",
+        (),
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS items_display_name ON items (display_name)",
+        (),
+    )?;
+    add_column_if_missing(tx, "listings", "sale_item_id", "int")?;
+    add_column_if_missing(tx, "listings", "buy_item_id", "int")?;
+    Ok(())
+}
+
+fn add_market_to_history_and_watches(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "listing_history", "guild_id", "int")?;
+    tx.execute(
+        "UPDATE listing_history SET guild_id = (
+            SELECT guild_id FROM listings WHERE listings.id = listing_history.listing_id
+        )
+        WHERE guild_id IS NULL",
+        (),
+    )?;
+    add_column_if_missing(tx, "watches", "guild_id", "int")?;
+    Ok(())
+}
+
+fn add_item_ids(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "listing_history", "sale_item_id", "int")?;
+    add_column_if_missing(tx, "listing_history", "buy_item_id", "int")?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS listing_history_item_ids ON listing_history (sale_item_id, buy_item_id)",
+        (),
+    )?;
+    add_column_if_missing(tx, "watches", "item_id", "int")?;
+    add_column_if_missing(tx, "watches", "counter_item_id", "int")?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS watches_item_id ON watches (item_id)",
+        (),
+    )?;
+    add_column_if_missing(tx, "trades", "sale_item_id", "int")?;
+    add_column_if_missing(tx, "trades", "buy_item_id", "int")?;
+    Ok(())
+}
+
+fn add_column_if_missing(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        (table, column),
+        |row| row.get(0),
+    )?;
+    if !exists {
+        tx.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            (),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The listings table as it was created before migrations existed
+    fn unversioned_db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS listings (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sale_quantity int,
+                sale_item text,
+                buy_quantity int,
+                buy_item text,
+                location_north int,
+                location_east int,
+                username text,
+                offer_count int,
+                description text DEFAULT '',
+                timestamp timestamp DEFAULT CURRENT_TIMESTAMP
+            )",
+            (),
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO listings (sale_quantity, sale_item, buy_quantity, buy_item, location_north, location_east, username, offer_count, description)
+            VALUES (1, 'Rough Cloth (T1)', 100, 'Hex Coin', 1000, 1000, 'trader', 3, 'cheap')",
+            (),
+        )
+        .unwrap();
+        db
+    }
+
+    fn columns(db: &Connection) -> Vec<String> {
+        let mut stmt = db
+            .prepare("SELECT name FROM pragma_table_info('listings')")
+            .unwrap();
+        let columns = stmt
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .unwrap();
+        columns
+    }
+
+    #[test]
+    fn migrates_empty_database() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION);
+        assert!(columns(&db).contains(&"user_id".to_string()));
+    }
+
+    #[test]
+    fn upgrades_unversioned_database_keeping_rows() {
+        let mut db = unversioned_db();
+        migrate(&mut db).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION);
+        let columns = columns(&db);
+        for column in ["user_id", "expires_at", "expiry_notified"] {
+            assert!(columns.contains(&column.to_string()), "missing {}", column);
+        }
+        let (username, description, notified): (String, String, i32) = db
+            .query_row(
+                "SELECT username, description, expiry_notified FROM listings",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(username, "trader");
+        assert_eq!(description, "cheap");
+        assert_eq!(notified, 0);
+    }
+
+    #[test]
+    fn tolerates_columns_added_before_versioning() {
+        let mut db = unversioned_db();
+        db.execute("ALTER TABLE listings ADD COLUMN user_id int", ())
+            .unwrap();
+        migrate(&mut db).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn rerunning_is_a_no_op() {
+        let mut db = unversioned_db();
+        migrate(&mut db).unwrap();
+        let before = columns(&db);
+        migrate(&mut db).unwrap();
+        assert_eq!(columns(&db), before);
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(migrate(&mut db).is_err());
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION + 1);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::Error;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+/// Where a user trades from, used when they leave out a location
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Home {
+    pub location_north: i32,
+    pub location_east: i32,
+    /// Default distance for searches
+    pub radius: Option<i32>,
+}
+
+/// A named place a user trades from, like a base or settlement
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedLocation {
+    pub name: String,
+    pub location_north: i32,
+    pub location_east: i32,
+}
+
+/// All reads & writes of the user_preferences & saved_locations tables
+pub struct PreferenceStore<'a> {
+    db: &'a Connection,
+}
+
+impl<'a> PreferenceStore<'a> {
+    pub fn new(db: &'a Connection) -> Self {
+        PreferenceStore { db }
+    }
+
+    /// Save a user's home, keeping their old radius if none is given
+    pub fn set_home(&self, user_id: u64, home: Home) -> Result<Home, Error> {
+        self.db.execute(
+            "INSERT INTO user_preferences (user_id, home_north, home_east, search_radius)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (user_id) DO UPDATE SET
+                home_north = excluded.home_north,
+                home_east = excluded.home_east,
+                search_radius = COALESCE(excluded.search_radius, search_radius)",
+            params![
+                user_id,
+                home.location_north,
+                home.location_east,
+                home.radius
+            ],
+        )?;
+        Ok(self.home(user_id)?.unwrap_or(home))
+    }
+
+    /// Get a user's home, `None` if they haven't set one
+    pub fn home(&self, user_id: u64) -> Result<Option<Home>, Error> {
+        Ok(self
+            .db
+            .query_row(
+                "SELECT home_north, home_east, search_radius FROM user_preferences
+                WHERE user_id = ? AND home_north IS NOT NULL AND home_east IS NOT NULL",
+                params![user_id],
+                |row| {
+                    Ok(Home {
+                        location_north: row.get(0)?,
+                        location_east: row.get(1)?,
+                        radius: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// Save a named location, replacing any of the user's locations with the same name
+    pub fn add_location(&self, user_id: u64, location: &SavedLocation) -> Result<(), Error> {
+        self.db.execute(
+            "INSERT INTO saved_locations (user_id, name, location_north, location_east)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (user_id, name) DO UPDATE SET
+                name = excluded.name,
+                location_north = excluded.location_north,
+                location_east = excluded.location_east",
+            params![
+                user_id,
+                location.name,
+                location.location_north,
+                location.location_east
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Delete one of a user's saved locations, returning whether it existed
+    pub fn remove_location(&self, user_id: u64, name: &str) -> Result<bool, Error> {
+        Ok(self.db.execute(
+            "DELETE FROM saved_locations WHERE user_id = ? AND name = ?",
+            params![user_id, name],
+        )? > 0)
+    }
+
+    /// Get one of a user's saved locations by name, ignoring case
+    pub fn location(&self, user_id: u64, name: &str) -> Result<Option<SavedLocation>, Error> {
+        Ok(self
+            .db
+            .query_row(
+                "SELECT name, location_north, location_east FROM saved_locations
+                WHERE user_id = ? AND name = ?",
+                params![user_id, name],
+                location_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Get all of a user's saved locations, by name
+    pub fn locations(&self, user_id: u64) -> Result<Vec<SavedLocation>, Error> {
+        let mut stmt = self.db.prepare(
+            "SELECT name, location_north, location_east FROM saved_locations
+            WHERE user_id = ? ORDER BY name",
+        )?;
+        let locations = stmt
+            .query_map(params![user_id], location_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(locations)
+    }
+}
+
+fn location_from_row(row: &Row) -> rusqlite::Result<SavedLocation> {
+    Ok(SavedLocation {
+        name: row.get(0)?,
+        location_north: row.get(1)?,
+        location_east: row.get(2)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[test]
+    fn set_home_keeps_radius_when_left_out() {
+        let db = test_db();
+        let store = PreferenceStore::new(&db);
+
+        assert_eq!(store.home(1).unwrap(), None);
+        store
+            .set_home(
+                1,
+                Home {
+                    location_north: 100,
+                    location_east: 200,
+                    radius: Some(50),
+                },
+            )
+            .unwrap();
+        let moved = store
+            .set_home(
+                1,
+                Home {
+                    location_north: -100,
+                    location_east: 0,
+                    radius: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            moved,
+            Home {
+                location_north: -100,
+                location_east: 0,
+                radius: Some(50),
+            }
+        );
+        assert_eq!(store.home(1).unwrap(), Some(moved));
+        assert_eq!(store.home(2).unwrap(), None);
+    }
+
+    #[test]
+    fn saved_locations_are_per_user_and_ignore_case() {
+        let db = test_db();
+        let store = PreferenceStore::new(&db);
+        let base = SavedLocation {
+            name: "Base".to_string(),
+            location_north: 100,
+            location_east: 200,
+        };
+        store.add_location(1, &base).unwrap();
+        store
+            .add_location(
+                1,
+                &SavedLocation {
+                    name: "Mine".to_string(),
+                    location_north: 0,
+                    location_east: 0,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(store.location(1, "base").unwrap(), Some(base.clone()));
+        assert_eq!(store.location(2, "base").unwrap(), None);
+
+        // Saving under an existing name moves it
+        let moved = SavedLocation {
+            name: "BASE".to_string(),
+            location_north: -5,
+            location_east: 5,
+        };
+        store.add_location(1, &moved).unwrap();
+        assert_eq!(store.locations(1).unwrap().len(), 2);
+        assert_eq!(store.location(1, "Base").unwrap(), Some(moved));
+
+        assert!(store.remove_location(1, "base").unwrap());
+        assert!(!store.remove_location(1, "base").unwrap());
+        assert_eq!(store.locations(1).unwrap().len(), 1);
+    }
+}
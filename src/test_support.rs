@@ -0,0 +1,114 @@
+use crate::catalog::{ItemCatalog, ItemDefinition, ItemKind};
+use crate::listings::{Listing, ListingStore};
+use crate::migrations;
+use rusqlite::Connection;
+
+/// Owner of the listings made by `listing`
+pub const TRADER: u64 = 1;
+
+/// Names & tiers of the items in every test database's catalog, in the order they get their IDs
+const ITEMS: &[(&str, Option<i32>)] = &[
+    ("Rough Cloth", Some(1)),
+    ("Hex Coin", None),
+    ("Fine Geode", Some(4)),
+    ("Wood", None),
+    ("Stone", None),
+];
+
+/// An in-memory database with every migration applied & the test items in its catalog
+pub fn test_db() -> Connection {
+    let mut db = Connection::open_in_memory().unwrap();
+    migrations::migrate(&mut db).unwrap();
+    let definitions = ITEMS
+        .iter()
+        .map(|&(name, tier)| ItemDefinition {
+            name: name.to_string(),
+            tier,
+            kind: ItemKind::Item,
+            aliases: vec![],
+        })
+        .collect();
+    ItemCatalog::sync(&db, definitions).unwrap();
+    db
+}
+
+/// ID of a test item by display name, e.g. "Rough Cloth (T1)"
+pub fn item_id(display_name: &str) -> i64 {
+    let position = ITEMS
+        .iter()
+        .position(|&(name, tier)| match tier {
+            Some(tier) => format!("{} (T{})", name, tier) == display_name,
+            None => name == display_name,
+        })
+        .unwrap_or_else(|| panic!("{} isn't a test item", display_name));
+    position as i64 + 1
+}
+
+/// A public listing of 1 `offer_item` for 100 `request_item` at N:0 E:0, owned by `TRADER`.
+/// Tests override the fields they care about
+pub fn listing(offer_item: &str, request_item: &str) -> Listing {
+    Listing {
+        id: 0,
+        offer_quantity: 1,
+        offer_item: offer_item.to_string(),
+        offer_item_id: Some(item_id(offer_item)),
+        request_quantity: 100,
+        request_item: request_item.to_string(),
+        request_item_id: Some(item_id(request_item)),
+        location_north: 0,
+        location_east: 0,
+        user: "trader".to_string(),
+        user_id: Some(TRADER),
+        guild_id: None,
+        offer_count: 1,
+        description: "".to_string(),
+        renewed_days_ago: 0,
+        distance: None,
+    }
+}
+
+/// Post a listing with a 5 day lifetime
+pub fn insert(db: &Connection, listing: Listing) -> Listing {
+    ListingStore::new(db).insert(listing, 5).unwrap()
+}
+
+/// Fields of a posted listing that tests change, `None` keeping the default from `listing`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListingOverrides {
+    pub owner: Option<u64>,
+    pub guild_id: Option<u64>,
+    pub offer_count: Option<i32>,
+    pub location_north: Option<i32>,
+}
+
+impl ListingOverrides {
+    /// Only the owner changed
+    pub fn owned_by(owner: u64) -> Self {
+        ListingOverrides {
+            owner: Some(owner),
+            ..Default::default()
+        }
+    }
+}
+
+/// Post a listing of `offer` for `request`, each a quantity & an item, otherwise like `listing`
+pub fn post(
+    db: &Connection,
+    offer: (i32, &str),
+    request: (i32, &str),
+    overrides: ListingOverrides,
+) -> Listing {
+    let listing = listing(offer.1, request.1);
+    insert(
+        db,
+        Listing {
+            offer_quantity: offer.0,
+            request_quantity: request.0,
+            user_id: overrides.owner.or(listing.user_id),
+            guild_id: overrides.guild_id.or(listing.guild_id),
+            offer_count: overrides.offer_count.unwrap_or(listing.offer_count),
+            location_north: overrides.location_north.unwrap_or(listing.location_north),
+            ..listing
+        },
+    )
+}
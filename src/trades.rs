@@ -0,0 +1,164 @@
+use crate::history::{self, ListingEvent};
+use crate::listings::ListingStore;
+use crate::Error;
+use rusqlite::{params, Connection};
+
+/// A completed trade against a listing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub id: i32,
+    pub listing_id: i32,
+    /// Owner of the listing
+    pub owner_id: u64,
+    /// The other side of the trade, if the owner named them
+    pub counterparty_id: Option<u64>,
+    pub offer_quantity: i32,
+    pub offer_item: String,
+    pub request_quantity: i32,
+    pub request_item: String,
+    /// Number of times the listing's offer was traded
+    pub quantity: i32,
+}
+
+/// Result of completing a trade against a listing
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompleteOutcome {
+    /// No listing with that ID belongs to the user
+    NotFound,
+    /// The listing has less stock than the trade needs
+    InsufficientStock(i32),
+    /// The trade was recorded & the listing has `remaining` stock left,
+    /// having been removed if that's zero
+    Completed { trade: Trade, remaining: i32 },
+}
+
+/// Record `quantity` of a listing's offers as traded, taking them out of its stock
+pub fn complete_listing(
+    db: &Connection,
+    listing_id: i32,
+    owner_id: u64,
+    quantity: i32,
+    counterparty_id: Option<u64>,
+) -> Result<CompleteOutcome, Error> {
+    let tx = db.unchecked_transaction()?;
+    let listing = match ListingStore::new(&tx).get(listing_id)? {
+        Some(listing) if listing.user_id == Some(owner_id) => listing,
+        _ => return Ok(CompleteOutcome::NotFound),
+    };
+    if quantity > listing.offer_count {
+        return Ok(CompleteOutcome::InsufficientStock(listing.offer_count));
+    }
+
+    tx.execute(
+        "INSERT INTO trades (listing_id, owner_id, counterparty_id, sale_quantity, sale_item, sale_item_id, buy_quantity, buy_item, buy_item_id, quantity)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            listing.id,
+            owner_id,
+            counterparty_id,
+            listing.offer_quantity,
+            listing.offer_item,
+            listing.offer_item_id,
+            listing.request_quantity,
+            listing.request_item,
+            listing.request_item_id,
+            quantity,
+        ],
+    )?;
+    let trade = Trade {
+        id: tx.last_insert_rowid() as i32,
+        listing_id: listing.id,
+        owner_id,
+        counterparty_id,
+        offer_quantity: listing.offer_quantity,
+        offer_item: listing.offer_item,
+        request_quantity: listing.request_quantity,
+        request_item: listing.request_item,
+        quantity,
+    };
+
+    let remaining = listing.offer_count - quantity;
+    if remaining == 0 {
+        history::record(&tx, ListingEvent::Completed, "id = ?", params![listing.id])?;
+        tx.execute("DELETE FROM listings WHERE id = ?", params![listing.id])?;
+    } else {
+        tx.execute(
+            "UPDATE listings SET offer_count = ? WHERE id = ?",
+            params![remaining, listing.id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(CompleteOutcome::Completed { trade, remaining })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{post, test_db, ListingOverrides};
+
+    fn trade_count(db: &Connection) -> i32 {
+        db.query_row("SELECT COUNT(*) FROM trades", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn decrements_stock_then_removes_listing() {
+        let db = test_db();
+        let listing = post(
+            &db,
+            (1, "Rough Cloth (T1)"),
+            (100, "Hex Coin"),
+            ListingOverrides {
+                offer_count: Some(3),
+                ..Default::default()
+            },
+        );
+
+        let outcome = complete_listing(&db, listing.id, 1, 2, Some(2)).unwrap();
+        let CompleteOutcome::Completed { trade, remaining } = outcome else {
+            panic!("expected a completed trade, got {:?}", outcome);
+        };
+        assert_eq!(remaining, 1);
+        assert_eq!(trade.counterparty_id, Some(2));
+        assert_eq!(trade.quantity, 2);
+        assert_eq!(
+            ListingStore::new(&db)
+                .get(listing.id)
+                .unwrap()
+                .unwrap()
+                .offer_count,
+            1
+        );
+
+        let outcome = complete_listing(&db, listing.id, 1, 1, None).unwrap();
+        assert!(matches!(
+            outcome,
+            CompleteOutcome::Completed { remaining: 0, .. }
+        ));
+        assert_eq!(ListingStore::new(&db).get(listing.id).unwrap(), None);
+        assert_eq!(trade_count(&db), 2);
+    }
+
+    #[test]
+    fn rejects_other_owners_and_missing_stock() {
+        let db = test_db();
+        let listing = post(
+            &db,
+            (1, "Rough Cloth (T1)"),
+            (100, "Hex Coin"),
+            ListingOverrides {
+                offer_count: Some(3),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            complete_listing(&db, listing.id, 2, 1, None).unwrap(),
+            CompleteOutcome::NotFound
+        );
+        assert_eq!(
+            complete_listing(&db, listing.id, 1, 4, None).unwrap(),
+            CompleteOutcome::InsufficientStock(3)
+        );
+        assert_eq!(trade_count(&db), 0);
+    }
+}
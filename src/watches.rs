@@ -0,0 +1,277 @@
+use crate::guilds::GuildStore;
+use crate::listings::Listing;
+use crate::Error;
+use rusqlite::{named_params, params, Connection, Row};
+
+/// A user's standing request to be told about new listings selling an item
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+    pub id: i32,
+    pub user_id: u64,
+    /// Catalog ID of the watched item
+    pub item_id: i64,
+    pub location_north: i32,
+    pub location_east: i32,
+    pub distance: i32,
+    /// Only listings asking for the item with this ID in return
+    pub counter_item_id: Option<i64>,
+    /// Only listings asking at most this much of the counter item per item
+    pub max_price: Option<f64>,
+    /// Guild the watch was set in, whose market decides which listings it hears about
+    pub guild_id: Option<u64>,
+}
+
+const WATCH_COLUMNS: &str =
+    "id, user_id, item_id, location_north, location_east, distance, counter_item_id, max_price, guild_id";
+
+fn watch_from_row(row: &Row) -> rusqlite::Result<Watch> {
+    Ok(Watch {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        item_id: row.get(2)?,
+        location_north: row.get(3)?,
+        location_east: row.get(4)?,
+        distance: row.get(5)?,
+        counter_item_id: row.get(6)?,
+        max_price: row.get(7)?,
+        guild_id: row.get(8)?,
+    })
+}
+
+/// All reads & writes of the watches table
+pub struct WatchStore<'a> {
+    db: &'a Connection,
+}
+
+impl<'a> WatchStore<'a> {
+    pub fn new(db: &'a Connection) -> Self {
+        WatchStore { db }
+    }
+
+    /// Insert a new watch, returning it with its assigned ID
+    pub fn insert(&self, watch: Watch) -> Result<Watch, Error> {
+        // The item names are only kept for linking watches from before item IDs
+        self.db.execute(
+            "INSERT INTO watches (user_id, item_id, item, location_north, location_east, distance, counter_item_id, counter_item, max_price, guild_id)
+            VALUES (
+                ?1, ?2, (SELECT display_name FROM items WHERE id = ?2), ?3, ?4, ?5,
+                ?6, (SELECT display_name FROM items WHERE id = ?6), ?7, ?8
+            )",
+            params![
+                watch.user_id,
+                watch.item_id,
+                watch.location_north,
+                watch.location_east,
+                watch.distance,
+                watch.counter_item_id,
+                watch.max_price,
+                watch.guild_id,
+            ],
+        )?;
+        Ok(Watch {
+            id: self.db.last_insert_rowid() as i32,
+            ..watch
+        })
+    }
+
+    /// Delete all of a user's watches on an item, returning how many were removed
+    pub fn delete_item(&self, user_id: u64, item_id: i64) -> Result<usize, Error> {
+        Ok(self.db.execute(
+            "DELETE FROM watches WHERE user_id = ? AND item_id = ?",
+            params![user_id, item_id],
+        )?)
+    }
+
+    /// Get all watches owned by a user
+    pub fn by_owner(&self, user_id: u64) -> Result<Vec<Watch>, Error> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM watches WHERE user_id = ? ORDER BY id",
+            WATCH_COLUMNS
+        ))?;
+        let watches = stmt
+            .query_map(params![user_id], watch_from_row)?
+            .collect::<rusqlite::Result<Vec<Watch>>>()?;
+        Ok(watches)
+    }
+
+    /// Number of watches owned by a user
+    pub fn count_by_owner(&self, user_id: u64) -> Result<i32, Error> {
+        Ok(self.db.query_row(
+            "SELECT COUNT(*) FROM watches WHERE user_id = ?",
+            params![user_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Get other users' watches that a newly posted listing satisfies. Listings in a private
+    /// market only reach watches set in that guild, & public listings reach every watch
+    /// whose market shows the public listings. Old watches on a counter item the catalog never
+    /// had match nothing
+    pub fn matching(&self, listing: &Listing) -> Result<Vec<Watch>, Error> {
+        let (listing_guild, _) = GuildStore::new(self.db)
+            .listing_market(listing.guild_id)?
+            .params();
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {}
+            FROM watches
+            WHERE item_id = :offer_item_id
+            AND MAX(ABS(location_north - :north), ABS(location_east - :east)) <= distance
+            AND (counter_item_id = :request_item_id OR (counter_item_id IS NULL AND counter_item IS NULL))
+            AND (max_price IS NULL OR :unit_price <= max_price)
+            AND (:user_id IS NULL OR user_id != :user_id)
+            AND CASE WHEN :listing_guild IS NOT NULL THEN guild_id = :listing_guild
+                ELSE guild_id IS NULL OR guild_id NOT IN (
+                    SELECT guild_id FROM guild_settings WHERE private_market AND NOT include_public
+                )
+            END
+            ORDER BY id",
+            WATCH_COLUMNS
+        ))?;
+        let watches = stmt
+            .query_map(
+                named_params! {
+                    ":offer_item_id": listing.offer_item_id,
+                    ":request_item_id": listing.request_item_id,
+                    ":north": listing.location_north,
+                    ":east": listing.location_east,
+                    ":unit_price": listing.unit_price(),
+                    ":user_id": listing.user_id,
+                    ":listing_guild": listing_guild,
+                },
+                watch_from_row,
+            )?
+            .collect::<rusqlite::Result<Vec<Watch>>>()?;
+        Ok(watches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guilds::GuildSettings;
+    use crate::test_support::{self, item_id, test_db};
+
+    fn watch(user_id: u64, item: &str) -> Watch {
+        Watch {
+            id: 0,
+            user_id,
+            item_id: item_id(item),
+            location_north: 0,
+            location_east: 0,
+            distance: 100,
+            counter_item_id: None,
+            max_price: None,
+            guild_id: None,
+        }
+    }
+
+    fn listing(offer_item: &str, request_quantity: i32, north: i32) -> Listing {
+        Listing {
+            id: 1,
+            request_quantity,
+            location_north: north,
+            ..test_support::listing(offer_item, "Hex Coin")
+        }
+    }
+
+    fn ids(watches: &[Watch]) -> Vec<i32> {
+        watches.iter().map(|w| w.id).collect()
+    }
+
+    #[test]
+    fn insert_count_and_delete() {
+        let db = test_db();
+        let store = WatchStore::new(&db);
+        let inserted = store.insert(watch(2, "Rough Cloth (T1)")).unwrap();
+        store.insert(watch(2, "Fine Geode (T4)")).unwrap();
+        assert_eq!(store.count_by_owner(2).unwrap(), 2);
+        assert_eq!(store.by_owner(2).unwrap()[0], inserted);
+        assert_eq!(
+            store.delete_item(3, item_id("Rough Cloth (T1)")).unwrap(),
+            0
+        );
+        assert_eq!(
+            store.delete_item(2, item_id("Rough Cloth (T1)")).unwrap(),
+            1
+        );
+        assert_eq!(store.count_by_owner(2).unwrap(), 1);
+    }
+
+    #[test]
+    fn matches_item_distance_and_price() {
+        let db = test_db();
+        let store = WatchStore::new(&db);
+        let any_price = store.insert(watch(2, "Rough Cloth (T1)")).unwrap();
+        let cheap_only = store
+            .insert(Watch {
+                counter_item_id: Some(item_id("Hex Coin")),
+                max_price: Some(50.0),
+                ..watch(3, "Rough Cloth (T1)")
+            })
+            .unwrap();
+        store
+            .insert(Watch {
+                counter_item_id: Some(item_id("Fine Geode (T4)")),
+                ..watch(4, "Rough Cloth (T1)")
+            })
+            .unwrap();
+        store.insert(watch(5, "Fine Geode (T4)")).unwrap();
+        // The seller's own watch
+        store.insert(watch(1, "Rough Cloth (T1)")).unwrap();
+
+        let cheap = listing("Rough Cloth (T1)", 40, 50);
+        assert_eq!(
+            ids(&store.matching(&cheap).unwrap()),
+            vec![any_price.id, cheap_only.id]
+        );
+        let pricey = listing("Rough Cloth (T1)", 100, 50);
+        assert_eq!(ids(&store.matching(&pricey).unwrap()), vec![any_price.id]);
+        let far = listing("Rough Cloth (T1)", 40, 500);
+        assert!(store.matching(&far).unwrap().is_empty());
+    }
+
+    #[test]
+    fn watches_stay_in_their_market() {
+        let db = test_db();
+        let guilds = GuildStore::new(&db);
+        for (guild_id, include_public) in [(10, false), (20, true)] {
+            guilds
+                .set(&GuildSettings {
+                    guild_id,
+                    private_market: true,
+                    include_public,
+                })
+                .unwrap();
+        }
+        let store = WatchStore::new(&db);
+        let public = store.insert(watch(2, "Rough Cloth (T1)")).unwrap();
+        let closed = store
+            .insert(Watch {
+                guild_id: Some(10),
+                ..watch(3, "Rough Cloth (T1)")
+            })
+            .unwrap();
+        let open = store
+            .insert(Watch {
+                guild_id: Some(20),
+                ..watch(4, "Rough Cloth (T1)")
+            })
+            .unwrap();
+
+        let from_dm = listing("Rough Cloth (T1)", 40, 0);
+        assert_eq!(
+            ids(&store.matching(&from_dm).unwrap()),
+            vec![public.id, open.id]
+        );
+        let from_closed = Listing {
+            guild_id: Some(10),
+            ..from_dm.clone()
+        };
+        assert_eq!(ids(&store.matching(&from_closed).unwrap()), vec![closed.id]);
+        let from_open = Listing {
+            guild_id: Some(20),
+            ..from_dm
+        };
+        assert_eq!(ids(&store.matching(&from_open).unwrap()), vec![open.id]);
+    }
+}